@@ -0,0 +1,77 @@
+use reinars::parse_stream;
+use reinars::sub_lus;
+use reinars::Stream;
+use reinars::SubLU;
+use std::time::Duration;
+use std::time::Instant;
+
+const ROUNDS: usize = 200;
+
+/// The characters the serializer escapes in a ling form.
+const RESERVED: &str = r"^$@*/<>{}\[]+";
+
+fn escape_each_char(out: &mut String, ling_form: &str) {
+    for c in ling_form.chars() {
+        if RESERVED.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+fn escape_unless_plain(out: &mut String, sub_lu: &SubLU) {
+    if sub_lu.ling_form_needs_escaping() {
+        escape_each_char(out, sub_lu.ling_form());
+    } else {
+        out.push_str(sub_lu.ling_form());
+    }
+}
+
+fn time<F: FnMut(&mut String)>(name: &str, mut write: F) -> Duration {
+    let mut out = String::new();
+    let mut bytes = 0;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        out.clear();
+        write(&mut out);
+        bytes += out.len();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} rounds, {} bytes in {:?} ({:.1} MB/s)",
+        name,
+        ROUNDS,
+        bytes,
+        elapsed,
+        bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0
+    );
+    elapsed
+}
+
+fn main() {
+    let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+    let (_, units) = parse_stream(&raw).unwrap();
+
+    // The same ling forms escaped both ways, to show what skipping the
+    // per-character scan for plain forms saves.
+    let forms: Vec<&SubLU> = sub_lus(&units).collect();
+    let each_char = time("escape every character", |out| {
+        forms
+            .iter()
+            .for_each(|sub_lu| escape_each_char(out, sub_lu.ling_form()))
+    });
+    let fast_path = time("skip plain ling forms", |out| {
+        forms
+            .iter()
+            .for_each(|sub_lu| escape_unless_plain(out, sub_lu))
+    });
+    println!(
+        "speedup: {:.1}x",
+        each_char.as_secs_f64() / fast_path.as_secs_f64()
+    );
+
+    let stream = Stream::from(units);
+    time("to_stream_string", |out| {
+        out.push_str(&stream.to_stream_string())
+    });
+}
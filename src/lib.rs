@@ -1,50 +1,926 @@
 use nom::branch::alt;
+use nom::bytes::complete::escaped;
 use nom::bytes::complete::escaped_transform;
 use nom::bytes::complete::is_not;
 use nom::bytes::complete::tag;
+use nom::bytes::complete::take_while;
+use nom::character::complete::anychar;
+use nom::character::complete::char;
 use nom::character::complete::one_of;
 use nom::character::complete::space1;
+use nom::combinator::map;
+use nom::combinator::opt;
+use nom::combinator::peek;
+use nom::combinator::recognize;
+use nom::combinator::verify;
 use nom::multi::many0;
 use nom::multi::separated_list0;
+use nom::multi::separated_list1;
+use nom::number::complete::recognize_float;
 use nom::sequence::delimited;
 use nom::sequence::pair;
+use nom::sequence::preceded;
+use nom::sequence::terminated;
 use nom::sequence::tuple;
 use nom::IResult;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+#[cfg(feature = "unicode-normalization")]
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Flag {
+    #[default]
     Nothing,
     Unanalyzed,
     Untranslated,
     UnableToGenerateOrStartOfInvariablePart,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SubLU {
     ling_form: String,
-    flag: Flag,
+    flags: Vec<Flag>,
     tags: Vec<String>,
+    joins_next: bool,
+    invariable: Option<String>,
+    weight: Option<String>,
+    raw_tag_spans: Option<Vec<String>>,
+    compound_boundaries: Vec<usize>,
+    gen_failed: bool,
+    delimited_chunk: bool,
 }
 
-#[derive(Debug, PartialEq)]
+impl SubLU {
+    pub fn ling_form(&self) -> &str {
+        &self.ling_form
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn joins_next(&self) -> bool {
+        self.joins_next
+    }
+
+    pub fn ling_form_needs_escaping(&self) -> bool {
+        needs_escaping(&self.ling_form)
+    }
+
+    pub fn lemma_head(&self) -> &str {
+        &self.ling_form
+    }
+
+    pub fn invariable(&self) -> Option<&str> {
+        self.invariable.as_deref()
+    }
+
+    pub fn weight(&self) -> Option<f64> {
+        self.weight.as_ref().and_then(|weight| weight.parse().ok())
+    }
+
+    /// Tags are stored unescaped and without their angle brackets; this
+    /// re-wraps them, escaping as the serializer does.
+    pub fn raw_tags(&self) -> impl Iterator<Item = String> + '_ {
+        self.tags.iter().map(|tag| {
+            let mut raw = String::with_capacity(tag.len() + 2);
+            write_escaped_tag(&mut raw, tag);
+            raw
+        })
+    }
+
+    /// Tags exactly as written, kept only when at least one was escaped.
+    pub fn raw_tag_spans(&self) -> Option<&[String]> {
+        self.raw_tag_spans.as_deref()
+    }
+
+    /// Clears what only a surface carries, the compound boundaries kept by
+    /// `ParserOptions::compound_surface`, leaving lemma, flags and tags.
+    pub fn into_lemma_only(mut self) -> SubLU {
+        self.compound_boundaries.clear();
+        self
+    }
+
+    /// The surface split on its compound boundaries; a single part unless
+    /// parsed with `ParserOptions::compound_surface`.
+    pub fn compound_parts(&self) -> Vec<&str> {
+        let mut parts = vec![];
+        let mut start = 0;
+        for &end in &self.compound_boundaries {
+            parts.push(&self.ling_form[start..end]);
+            start = end;
+        }
+        parts.push(&self.ling_form[start..]);
+        parts
+    }
+
+    /// Set for a trailing `+?` when parsed with
+    /// `ParserOptions::gen_failure_marker`.
+    pub fn gen_failed(&self) -> bool {
+        self.gen_failed
+    }
+
+    /// The first of the leading flags, or `Flag::Nothing`.
+    pub fn flag(&self) -> &Flag {
+        self.flags.first().unwrap_or(&Flag::Nothing)
+    }
+
+    /// All leading flags in the order they were written, as in `^*@word$`.
+    pub fn flags(&self) -> &[Flag] {
+        &self.flags
+    }
+
+    pub fn set_flag(&mut self, flag: Flag) {
+        self.flags.clear();
+        if flag != Flag::Nothing {
+            self.flags.push(flag);
+        }
+    }
+
+    pub fn truncate_tags(&mut self, n: usize) {
+        self.tags.truncate(n);
+        if let Some(spans) = &mut self.raw_tag_spans {
+            spans.truncate(n);
+        }
+    }
+
+    /// Splits each tag on its first `:`, as in `<case:nom>`.
+    pub fn tag_kv(&self) -> impl Iterator<Item = (&str, Option<&str>)> + '_ {
+        self.tags.iter().map(|tag| match tag.split_once(':') {
+            Some((key, value)) => (key, Some(value)),
+            None => (tag.as_str(), None),
+        })
+    }
+
+    /// Splits each tag on `|`, as in `<m|f>`.
+    pub fn tag_alternatives(&self) -> Vec<Vec<&str>> {
+        self.tags
+            .iter()
+            .map(|tag| tag.split('|').collect())
+            .collect()
+    }
+
+    /// Bit `i` is set when `schema` tag `i` is present.
+    pub fn tag_bitset(&self, schema: &TagSchema) -> u64 {
+        schema
+            .tags
+            .iter()
+            .enumerate()
+            .filter(|(_, tag)| self.tags.contains(tag))
+            .fold(0, |bits, (i, _)| bits | 1 << i)
+    }
+}
+
+/// A borrowed `SubLU` that only allocates for a ling form with escapes.
+/// Tags are kept as written, and `j/`, `#` and weights get no special
+/// treatment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubLuRef<'a> {
+    pub ling_form: Cow<'a, str>,
+    pub tags: Vec<&'a str>,
+    pub flags: Vec<Flag>,
+}
+
+impl SubLuRef<'_> {
+    /// The first of the leading flags, or `Flag::Nothing`.
+    pub fn flag(&self) -> &Flag {
+        self.flags.first().unwrap_or(&Flag::Nothing)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SubLuBuilder {
+    sub_lu: SubLU,
+}
+
+impl SubLuBuilder {
+    pub fn new(ling_form: &str) -> SubLuBuilder {
+        SubLuBuilder::default().ling_form(ling_form)
+    }
+
+    pub fn ling_form(mut self, ling_form: &str) -> SubLuBuilder {
+        self.sub_lu.ling_form = String::from(ling_form);
+        self
+    }
+
+    /// Adds a flag after any set before, so flags can be stacked.
+    pub fn flag(mut self, flag: Flag) -> SubLuBuilder {
+        if flag != Flag::Nothing {
+            self.sub_lu.flags.push(flag);
+        }
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> SubLuBuilder {
+        self.sub_lu.tags.push(String::from(tag));
+        self
+    }
+
+    pub fn tags(mut self, tags: &[&str]) -> SubLuBuilder {
+        self.sub_lu
+            .tags
+            .extend(tags.iter().map(|tag| String::from(*tag)));
+        self
+    }
+
+    pub fn build(self) -> SubLU {
+        self.sub_lu
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagSchema {
+    tags: Vec<String>,
+}
+
+impl TagSchema {
+    /// Panics when given more than 64 tags, the width of a bitset.
+    pub fn new(tags: Vec<&str>) -> TagSchema {
+        assert!(tags.len() <= 64, "a tag schema holds at most 64 tags");
+        TagSchema {
+            tags: tags.into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+/// The readings of a `^...$` unit. When there is more than one and the
+/// first has no tags, that one is the surface form and the rest are its
+/// analyses, so `^a<n>/a<adj>$` is all analyses. `wordbound` is a
+/// `[[...]]` wordbound blank written right before the unit, and `surface`
+/// the surface text kept by `ParserOptions::keep_surface`, in which case
+/// the readings are all analyses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LexicalUnit {
+    readings: Vec<SubLU>,
+    wordbound: Option<String>,
+    surface: Option<String>,
+}
+
+impl LexicalUnit {
+    pub fn wordbound(&self) -> Option<&str> {
+        self.wordbound.as_deref()
+    }
+
+    /// The surface text kept by `ParserOptions::keep_surface`.
+    pub fn kept_surface(&self) -> Option<&str> {
+        self.surface.as_deref()
+    }
+
+    fn surface_len(&self) -> usize {
+        match self.surface {
+            Some(_) => 0,
+            None => surface_len(&self.readings, |sub_lu| sub_lu.tags.is_empty()),
+        }
+    }
+
+    pub fn surface(&self) -> Option<&SubLU> {
+        self.readings[..self.surface_len()].first()
+    }
+
+    pub fn analyses(&self) -> &[SubLU] {
+        &self.readings[self.surface_len()..]
+    }
+
+    pub fn is_ambiguous(&self) -> bool {
+        self.analyses().len() > 1
+    }
+
+    /// Drops the surface form, whether it is a reading or kept apart by
+    /// `ParserOptions::keep_surface`, and turns each analysis lemma-only.
+    pub fn into_lemma_only(mut self) -> LexicalUnit {
+        let surface_len = self.surface_len();
+        self.surface = None;
+        self.readings = self
+            .readings
+            .into_iter()
+            .skip(surface_len)
+            .map(SubLU::into_lemma_only)
+            .collect();
+        self
+    }
+
+    pub fn into_readings(self) -> Vec<SubLU> {
+        self.readings
+    }
+}
+
+/// 1 when the first of several readings is a surface form, going by
+/// `is_untagged`, otherwise 0.
+fn surface_len<T>(readings: &[T], is_untagged: impl Fn(&T) -> bool) -> usize {
+    match readings {
+        [first, _, ..] if is_untagged(first) => 1,
+        _ => 0,
+    }
+}
+
+/// The analyses of a joined unit, leaving out a surface form as
+/// `LexicalUnit::analyses` does.
+fn joined_analyses(analyses: &[Vec<SubLU>]) -> &[Vec<SubLU>] {
+    let untagged = |sub_lus: &Vec<SubLU>| sub_lus.iter().all(|sub_lu| sub_lu.tags.is_empty());
+    &analyses[surface_len(analyses, untagged)..]
+}
+
+impl From<Vec<SubLU>> for LexicalUnit {
+    fn from(readings: Vec<SubLU>) -> Self {
+        LexicalUnit {
+            readings,
+            ..Default::default()
+        }
+    }
+}
+
+impl std::ops::Deref for LexicalUnit {
+    type Target = Vec<SubLU>;
+
+    fn deref(&self) -> &Vec<SubLU> {
+        &self.readings
+    }
+}
+
+impl std::ops::DerefMut for LexicalUnit {
+    fn deref_mut(&mut self) -> &mut Vec<SubLU> {
+        &mut self.readings
+    }
+}
+
+/// With the `serde` feature, a unit serializes as `{"type": ..., "value": ...}`
+/// with the variant name as its type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "value"))]
 pub enum StreamUnit {
-    LexicalUnit(Vec<SubLU>),
+    LexicalUnit(LexicalUnit),
     Space(String),
     Format(String),
+    Superblank(String),
+    NullFlush,
     JoinedLexicalUnit(Vec<Vec<SubLU>>),
     Chunk(SubLU, Vec<StreamUnit>),
+    Preamble(String),
+    Unknown(String),
 }
 
-pub fn parse_tag(input: &str) -> IResult<&str, &str> {
-    let mut parse = delimited(tag("<"), is_not(r#"<>"#), tag(">"));
-    parse(input)
+impl StreamUnit {
+    pub fn lexical_unit(analyses: Vec<SubLU>) -> StreamUnit {
+        StreamUnit::LexicalUnit(LexicalUnit::from(analyses))
+    }
+
+    /// The surface text kept by `ParserOptions::keep_surface`.
+    pub fn surface(&self) -> Option<&str> {
+        match self {
+            StreamUnit::LexicalUnit(lu) => lu.kept_surface(),
+            _ => None,
+        }
+    }
+
+    pub fn is_lexical_unit(&self) -> bool {
+        matches!(
+            self,
+            StreamUnit::LexicalUnit(_) | StreamUnit::JoinedLexicalUnit(_)
+        )
+    }
+
+    /// The first analysis after any surface form, as told apart by
+    /// `LexicalUnit::analyses`; for a joined unit, its first sub-unit.
+    pub fn first_analysis(&self) -> Option<&SubLU> {
+        match self {
+            StreamUnit::LexicalUnit(lu) => lu.analyses().first(),
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                joined_analyses(analyses).first().and_then(|g| g.first())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_sentence_boundary(&self) -> bool {
+        let has_sent_tag = |sub_lu: &SubLU| sub_lu.tags.iter().any(|tag| tag == "sent");
+        match self {
+            StreamUnit::LexicalUnit(analyses) => analyses.iter().any(has_sent_tag),
+            StreamUnit::JoinedLexicalUnit(analyses) => analyses.iter().flatten().any(has_sent_tag),
+            _ => false,
+        }
+    }
+
+    /// Also accepts a unit whose ling form is the configured
+    /// `ParserOptions::sentence_sentinel`. An empty sentinel matches
+    /// nothing, as every tag-only unit such as `^<S>$` would match it.
+    pub fn is_sentence_boundary_with(&self, options: &ParserOptions) -> bool {
+        let is_sentinel = match (&options.sentence_sentinel, self.first_analysis()) {
+            (Some(sentinel), Some(sub_lu)) if !sentinel.is_empty() => sub_lu.ling_form == *sentinel,
+            _ => false,
+        };
+        is_sentinel || self.is_sentence_boundary()
+    }
+
+    /// The only analysis of a unit, not counting a surface form, so
+    /// `^dogs/dog<n>$` gives `dog<n>`.
+    pub fn as_single_sub_lu(&self) -> Option<&SubLU> {
+        match self {
+            StreamUnit::LexicalUnit(lu) => match lu.analyses() {
+                [analysis] => Some(analysis),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn zip_join_groups<'a>(
+        &'a self,
+        other: &'a StreamUnit,
+    ) -> Option<Vec<(&'a Vec<SubLU>, &'a Vec<SubLU>)>> {
+        match (self, other) {
+            (StreamUnit::JoinedLexicalUnit(a), StreamUnit::JoinedLexicalUnit(b))
+                if a.len() == b.len() =>
+            {
+                Some(a.iter().zip(b).collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// Sums the weights of the first analysis after any surface form, over
+    /// all of its sub-units for a joined unit; `None` when none of them
+    /// carries a weight.
+    pub fn total_weight(&self) -> Option<f64> {
+        let first: &[SubLU] = match self {
+            StreamUnit::LexicalUnit(lu) => lu
+                .analyses()
+                .first()
+                .map(std::slice::from_ref)
+                .unwrap_or_default(),
+            StreamUnit::JoinedLexicalUnit(analyses) => joined_analyses(analyses)
+                .first()
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+            _ => &[],
+        };
+        first
+            .iter()
+            .filter_map(SubLU::weight)
+            .fold(None, |total, weight| Some(total.unwrap_or(0.0) + weight))
+    }
+
+    pub fn last_analysis(&self) -> Option<&SubLU> {
+        self.analyses_rev().next()
+    }
+
+    pub fn analyses_rev(&self) -> impl Iterator<Item = &SubLU> {
+        let (analyses, joined): (&[SubLU], &[Vec<SubLU>]) = match self {
+            StreamUnit::LexicalUnit(analyses) => (analyses, &[]),
+            StreamUnit::JoinedLexicalUnit(joined) => (&[], joined),
+            _ => (&[], &[]),
+        };
+        analyses.iter().rev().chain(joined.iter().flatten().rev())
+    }
+
+    pub fn depth(&self) -> usize {
+        match self {
+            StreamUnit::Chunk(_, children) => 1 + max_chunk_depth(children),
+            _ => 0,
+        }
+    }
+
+    pub fn ling_forms(&self) -> Vec<&str> {
+        match self {
+            StreamUnit::LexicalUnit(analyses) => analyses
+                .iter()
+                .map(|sub_lu| sub_lu.ling_form.as_str())
+                .collect(),
+            StreamUnit::JoinedLexicalUnit(analyses) => analyses
+                .iter()
+                .flatten()
+                .map(|sub_lu| sub_lu.ling_form.as_str())
+                .collect(),
+            StreamUnit::Chunk(head, _) => vec![head.ling_form.as_str()],
+            _ => vec![],
+        }
+    }
+
+    pub fn tag_count(&self) -> usize {
+        let count = |sub_lu: &SubLU| sub_lu.tags.len();
+        match self {
+            StreamUnit::LexicalUnit(analyses) => analyses.iter().map(count).sum(),
+            StreamUnit::JoinedLexicalUnit(analyses) => analyses.iter().flatten().map(count).sum(),
+            StreamUnit::Chunk(head, children) => count(head) + tag_count(children),
+            _ => 0,
+        }
+    }
+
+    /// True when the first analysis after any surface form is flagged `*`,
+    /// as in `^dogz/*dogz$`.
+    pub fn is_unknown(&self) -> bool {
+        self.first_analysis()
+            .is_some_and(|sub_lu| sub_lu.flags.contains(&Flag::Unanalyzed))
+    }
+
+    /// One unit per analysis, leaving out a surface form as
+    /// `select_nth_analysis` does; each unit keeps the wordbound blank and
+    /// kept surface of the original.
+    pub fn explode_analyses(self) -> Vec<StreamUnit> {
+        match self {
+            StreamUnit::LexicalUnit(lu) => {
+                let surface_len = lu.surface_len();
+                let LexicalUnit {
+                    readings,
+                    wordbound,
+                    surface,
+                } = lu;
+                readings
+                    .into_iter()
+                    .skip(surface_len)
+                    .map(|analysis| {
+                        StreamUnit::LexicalUnit(LexicalUnit {
+                            readings: vec![analysis],
+                            wordbound: wordbound.clone(),
+                            surface: surface.clone(),
+                        })
+                    })
+                    .collect()
+            }
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                let surface_len = analyses.len() - joined_analyses(&analyses).len();
+                analyses
+                    .into_iter()
+                    .skip(surface_len)
+                    .map(|analysis| StreamUnit::JoinedLexicalUnit(vec![analysis]))
+                    .collect()
+            }
+            _ => vec![self],
+        }
+    }
+
+    /// Drops the surface side (`^the/el<det>$` becomes `^el<det>$`), as
+    /// told apart by `LexicalUnit::analyses`, so `^a<n>/a<adj>$` keeps both
+    /// analyses.
+    pub fn into_lemma_only(self) -> StreamUnit {
+        match self {
+            StreamUnit::LexicalUnit(lu) => StreamUnit::LexicalUnit(lu.into_lemma_only()),
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                let surface_len = analyses.len() - joined_analyses(&analyses).len();
+                let analyses = analyses
+                    .into_iter()
+                    .skip(surface_len)
+                    .map(|sub_lus| sub_lus.into_iter().map(SubLU::into_lemma_only).collect())
+                    .collect();
+                StreamUnit::JoinedLexicalUnit(analyses)
+            }
+            StreamUnit::Chunk(head, children) => StreamUnit::Chunk(head, into_lemma_only(children)),
+            _ => self,
+        }
+    }
+
+    /// The number of `+`-separated sub-units, taking the largest over the
+    /// `/`-separated analyses so a plain surface reading does not hide it.
+    pub fn join_group_count(&self) -> Option<usize> {
+        match self {
+            StreamUnit::JoinedLexicalUnit(analyses) => analyses.iter().map(Vec::len).max(),
+            _ => None,
+        }
+    }
+
+    pub fn has_mixed_flags(&self) -> bool {
+        fn mixed<'a>(mut flags: impl Iterator<Item = &'a Flag>) -> bool {
+            match flags.next() {
+                Some(first) => flags.any(|flag| flag != first),
+                None => false,
+            }
+        }
+        match self {
+            StreamUnit::LexicalUnit(analyses) => mixed(analyses.iter().map(SubLU::flag)),
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                mixed(analyses.iter().flatten().map(SubLU::flag))
+            }
+            _ => false,
+        }
+    }
+
+    /// Compares joined units analysis by analysis, ignoring the order of
+    /// the `+`-separated sub-units within each.
+    pub fn eq_join_unordered(&self, other: &StreamUnit) -> bool {
+        fn counts(sub_lus: &[SubLU]) -> HashMap<&SubLU, usize> {
+            let mut counts = HashMap::new();
+            for sub_lu in sub_lus {
+                *counts.entry(sub_lu).or_insert(0) += 1;
+            }
+            counts
+        }
+        match (self, other) {
+            (StreamUnit::JoinedLexicalUnit(a), StreamUnit::JoinedLexicalUnit(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| counts(a) == counts(b))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn with_chunk_children<F: FnOnce(Vec<StreamUnit>) -> Vec<StreamUnit>>(
+        self,
+        f: F,
+    ) -> StreamUnit {
+        match self {
+            StreamUnit::Chunk(head, children) => StreamUnit::Chunk(head, f(children)),
+            _ => self,
+        }
+    }
+
+    pub fn chunk_to_head_lu(self) -> StreamUnit {
+        match self {
+            StreamUnit::Chunk(mut head, _) => {
+                head.delimited_chunk = false;
+                StreamUnit::lexical_unit(vec![head])
+            }
+            _ => self,
+        }
+    }
 }
 
-pub fn parse_basic_lu(input: &str) -> IResult<&str, StreamUnit> {
-    let parse_analyses = separated_list0(tag("/"), parse_sub_lu);
-    let mut parse = delimited(tag("^"), parse_analyses, tag("$"));
-    let res = parse(input);
-    res.map(|(i, o)| (i, StreamUnit::LexicalUnit(o)))
+/// What a parsed stream keeps besides its units: a `#!` header line skipped
+/// by `ParserOptions::shebang`, and the source text retained by
+/// `Stream::with_source`. Neither takes part in equality.
+#[derive(Debug, Clone, Default)]
+struct Retained {
+    shebang: Option<String>,
+    source: Option<String>,
+    dirty: bool,
+}
+
+impl PartialEq for Retained {
+    fn eq(&self, other: &Retained) -> bool {
+        self.shebang == other.shebang
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    units: Vec<StreamUnit>,
+    retained: Retained,
+}
+
+impl Stream {
+    pub fn shebang(&self) -> Option<&str> {
+        self.retained.shebang.as_deref()
+    }
+
+    /// Keeps `source` so that `to_stream_string` can return it verbatim until
+    /// the stream is changed through `units_mut`. A source that does not
+    /// parse, in full and with the default options, into these units is not
+    /// kept.
+    pub fn with_source(mut self, source: &str) -> Stream {
+        let options = ParserOptions {
+            shebang: self.retained.shebang.is_some(),
+            ..Default::default()
+        };
+        if parse(source, &options).is_ok_and(|parsed| parsed.units == self.units) {
+            self.retained.source = Some(String::from(source));
+            self.retained.dirty = false;
+        }
+        self
+    }
+
+    pub fn units(&self) -> &[StreamUnit] {
+        &self.units
+    }
+
+    /// Marks the stream as changed, so a source kept by `with_source` is no
+    /// longer returned.
+    pub fn units_mut(&mut self) -> &mut Vec<StreamUnit> {
+        self.retained.dirty = true;
+        &mut self.units
+    }
+
+    pub fn into_units(self) -> Vec<StreamUnit> {
+        self.units
+    }
+
+    /// The source kept by `with_source`, while the stream is unchanged.
+    fn unchanged_source(&self) -> Option<&str> {
+        match &self.retained.source {
+            Some(source) if !self.retained.dirty => Some(source),
+            _ => None,
+        }
+    }
+
+    /// With `with_space`, a single space goes in first when the previous
+    /// unit is a lexical unit.
+    pub fn push_unit(&mut self, unit: StreamUnit, with_space: bool) {
+        let units = self.units_mut();
+        if with_space && units.last().is_some_and(StreamUnit::is_lexical_unit) {
+            units.push(StreamUnit::Space(String::from(" ")));
+        }
+        units.push(unit);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.retained.dirty
+    }
+}
+
+impl From<Vec<StreamUnit>> for Stream {
+    fn from(units: Vec<StreamUnit>) -> Self {
+        Stream {
+            units,
+            retained: Retained::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Syntax { offset: usize },
+    UnexpectedDollar { offset: usize },
+    UnknownTag { tag: String, offset: usize },
+    EmptyTag { offset: usize },
+    TrailingInput { offset: usize, leftover: String },
+    UnterminatedFormat { offset: usize },
+    UnterminatedLexicalUnit { offset: usize },
+    TooManyUnits { offset: usize },
+    ChunkTooDeep { offset: usize },
+    UnitTooLarge { offset: usize },
+    InvalidUtf8 { offset: usize },
+    Io(io::ErrorKind),
+}
+
+/// A problem that lenient mode repaired instead of rejecting.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseWarning {
+    MissingAnalysisSeparator { offset: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax { offset } => write!(f, "syntax error at byte {}", offset),
+            ParseError::UnexpectedDollar { offset } => {
+                write!(f, "unexpected '$' at byte {}", offset)
+            }
+            ParseError::UnknownTag { tag, offset } => {
+                write!(f, "unknown tag <{}> at byte {}", tag, offset)
+            }
+            ParseError::EmptyTag { offset } => write!(f, "empty tag <> at byte {}", offset),
+            ParseError::TrailingInput { offset, leftover } => {
+                write!(f, "unparsed input {:?} at byte {}", leftover, offset)
+            }
+            ParseError::UnterminatedFormat { offset } => {
+                write!(f, "unterminated format at byte {}", offset)
+            }
+            ParseError::UnterminatedLexicalUnit { offset } => {
+                write!(f, "unterminated lexical unit at byte {}", offset)
+            }
+            ParseError::TooManyUnits { offset } => write!(f, "too many units at byte {}", offset),
+            ParseError::ChunkTooDeep { offset } => {
+                write!(f, "chunk nested too deep at byte {}", offset)
+            }
+            ParseError::UnitTooLarge { offset } => write!(f, "unit too large at byte {}", offset),
+            ParseError::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 at byte {}", offset),
+            ParseError::Io(kind) => write!(f, "I/O error: {:?}", kind),
+        }
+    }
+}
+
+impl ParseError {
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            ParseError::Syntax { offset }
+            | ParseError::UnexpectedDollar { offset }
+            | ParseError::UnknownTag { offset, .. }
+            | ParseError::EmptyTag { offset }
+            | ParseError::TrailingInput { offset, .. }
+            | ParseError::UnterminatedFormat { offset }
+            | ParseError::UnterminatedLexicalUnit { offset }
+            | ParseError::TooManyUnits { offset }
+            | ParseError::ChunkTooDeep { offset }
+            | ParseError::UnitTooLarge { offset }
+            | ParseError::InvalidUtf8 { offset } => Some(*offset),
+            ParseError::Io(_) => None,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ParseError::Syntax { .. } => "syntax error",
+            ParseError::UnexpectedDollar { .. } => "unexpected '$'",
+            ParseError::UnknownTag { .. } => "unknown tag",
+            ParseError::EmptyTag { .. } => "empty tag",
+            ParseError::TrailingInput { .. } => "unparsed input",
+            ParseError::UnterminatedFormat { .. } => "unterminated format",
+            ParseError::UnterminatedLexicalUnit { .. } => "unterminated lexical unit",
+            ParseError::TooManyUnits { .. } => "too many units",
+            ParseError::ChunkTooDeep { .. } => "chunk nested too deep",
+            ParseError::UnitTooLarge { .. } => "unit too large",
+            ParseError::InvalidUtf8 { .. } => "invalid UTF-8",
+            ParseError::Io(_) => "I/O error",
+        }
+    }
+
+    /// The part of `input` left unparsed, for the input this error came from.
+    pub fn remaining<'a>(&self, input: &'a str) -> &'a str {
+        let offset = self.offset().unwrap_or(0).min(input.len());
+        input.get(offset..).unwrap_or("")
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e.kind())
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> io::Error {
+        match e {
+            ParseError::Io(kind) => io::Error::from(kind),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+pub type TagValidator = Box<dyn Fn(&str) -> bool>;
+
+pub struct ParserOptions {
+    pub preamble: bool,
+    pub lenient: bool,
+    pub tag_validator: Option<TagValidator>,
+    pub chunk_open: char,
+    pub chunk_close: char,
+    pub weights: bool,
+    pub sentence_sentinel: Option<String>,
+    pub quoted_lemmas: bool,
+    pub compound_surface: bool,
+    pub shebang: bool,
+    pub gen_failure_marker: bool,
+    pub max_units: Option<usize>,
+    pub max_chunk_depth: Option<usize>,
+    pub max_unit_bytes: Option<usize>,
+    pub keep_surface: bool,
+    pub chunk_trailing_dollar: bool,
+    #[cfg(feature = "unicode-normalization")]
+    pub normalize_nfc: bool,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            preamble: false,
+            lenient: false,
+            tag_validator: None,
+            chunk_open: '{',
+            chunk_close: '}',
+            weights: false,
+            sentence_sentinel: None,
+            quoted_lemmas: false,
+            compound_surface: false,
+            shebang: false,
+            gen_failure_marker: false,
+            max_units: None,
+            max_chunk_depth: None,
+            max_unit_bytes: None,
+            keep_surface: false,
+            chunk_trailing_dollar: true,
+            #[cfg(feature = "unicode-normalization")]
+            normalize_nfc: false,
+        }
+    }
+}
+
+impl fmt::Debug for ParserOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("ParserOptions");
+        debug
+            .field("preamble", &self.preamble)
+            .field("lenient", &self.lenient)
+            .field("tag_validator", &self.tag_validator.as_ref().map(|_| ".."))
+            .field("chunk_open", &self.chunk_open)
+            .field("chunk_close", &self.chunk_close)
+            .field("weights", &self.weights)
+            .field("sentence_sentinel", &self.sentence_sentinel)
+            .field("quoted_lemmas", &self.quoted_lemmas)
+            .field("compound_surface", &self.compound_surface)
+            .field("shebang", &self.shebang)
+            .field("gen_failure_marker", &self.gen_failure_marker)
+            .field("max_units", &self.max_units)
+            .field("max_chunk_depth", &self.max_chunk_depth)
+            .field("max_unit_bytes", &self.max_unit_bytes)
+            .field("keep_surface", &self.keep_surface)
+            .field("chunk_trailing_dollar", &self.chunk_trailing_dollar);
+        #[cfg(feature = "unicode-normalization")]
+        debug.field("normalize_nfc", &self.normalize_nfc);
+        debug.finish()
+    }
 }
 
 pub fn make_flag(s: &str) -> Flag {
@@ -56,403 +932,4554 @@ pub fn make_flag(s: &str) -> Flag {
     }
 }
 
-pub fn parse_sub_lu_basic(input: &str) -> IResult<&str, SubLU> {
-    let ling_form_inner_parse = is_not(r#"^$@*/<>{}\[]"#);
-    let ling_form_escape_parse =
-        escaped_transform(ling_form_inner_parse, '\\', one_of(r#"^$@*/<>{}\[]"#));
-    let mut parse = tuple((
-        alt((tag("*"), tag("#"), tag("@"), tag(""))),
-        ling_form_escape_parse,
-        many0(parse_tag),
-    ));
-    parse(input).map(|(i, (flag, ling_form, tags))| {
-        (
-            i,
-            SubLU {
-                ling_form: ling_form.to_string(),
-                tags: tags.iter().map(|tag| String::from(*tag)).collect(),
-                flag: make_flag(flag),
-            },
-        )
-    })
+/// One flag per symbol in a run such as `*@`; `Flag::Nothing` is left out.
+pub fn make_flags(s: &str) -> Vec<Flag> {
+    s.chars()
+        .map(|c| make_flag(c.encode_utf8(&mut [0; 4])))
+        .filter(|flag| *flag != Flag::Nothing)
+        .collect()
 }
 
-pub fn parse_sub_lu_without_ling_form(input: &str) -> IResult<&str, SubLU> {
-    let mut parse = tuple((
-        alt((tag("*"), tag("#"), tag("@"), tag(""))),
-        many0(parse_tag),
-    ));
-    parse(input).map(|(i, (flag, tags))| {
-        (
-            i,
-            SubLU {
-                ling_form: String::from(""),
-                tags: tags.iter().map(|tag| String::from(*tag)).collect(),
-                flag: make_flag(flag),
-            },
-        )
-    })
-}
+#[derive(Debug, PartialEq)]
+pub struct InvalidFlag(pub String);
 
-pub fn parse_sub_lu(input: &str) -> IResult<&str, SubLU> {
-    alt((parse_sub_lu_basic, parse_sub_lu_without_ling_form))(input)
+impl fmt::Display for InvalidFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid flag {:?}", self.0)
+    }
 }
 
-pub fn parse_joined_lu(input: &str) -> IResult<&str, StreamUnit> {
-    let parse_sub_lus = separated_list0(tag("+"), parse_sub_lu);
-    let parse_analyses = separated_list0(tag("/"), parse_sub_lus);
-    let mut parse = delimited(tag("^"), parse_analyses, tag("$"));
-    let res = parse(input);
-    res.map(|(i, o)| (i, StreamUnit::JoinedLexicalUnit(o)))
-}
+impl std::error::Error for InvalidFlag {}
 
-pub fn parse_lu_or_space_or_format(input: &str) -> IResult<&str, StreamUnit> {
-    alt((parse_format, parse_basic_lu, parse_joined_lu, parse_space))(input)
-}
+impl TryFrom<char> for Flag {
+    type Error = InvalidFlag;
 
-pub fn parse_chunk(input: &str) -> IResult<&str, StreamUnit> {
-    let parse_children = delimited(tag("{"), many0(parse_lu_or_space_or_format), tag("}"));
-    let mut parse = pair(parse_sub_lu, parse_children);
-    let res = parse(input);
-    res.map(|(i, (head, children))| (i, StreamUnit::Chunk(head, children)))
+    fn try_from(c: char) -> Result<Flag, InvalidFlag> {
+        match c {
+            '*' | '@' | '#' => Ok(make_flag(c.encode_utf8(&mut [0; 4]))),
+            _ => Err(InvalidFlag(c.to_string())),
+        }
+    }
 }
 
-pub fn parse_format(input: &str) -> IResult<&str, StreamUnit> {
-    let mut parse = delimited(tag("["), is_not(r#"[]"#), tag("]"));
-    let res = parse(input);
-    res.map(|(i, o)| (i, StreamUnit::Format(String::from(o))))
+impl std::str::FromStr for Flag {
+    type Err = InvalidFlag;
+
+    fn from_str(s: &str) -> Result<Flag, InvalidFlag> {
+        match s {
+            "*" | "@" | "#" | "" => Ok(make_flag(s)),
+            _ => Err(InvalidFlag(String::from(s))),
+        }
+    }
 }
 
-pub fn parse_space(input: &str) -> IResult<&str, StreamUnit> {
-    alt((space1, tag("\n")))(input).map(|(i, o)| (i, StreamUnit::Space(String::from(o))))
+const JOIN_TAG: &str = "j/";
+
+const FLAG_SYMBOLS: &str = "*@#";
+
+const INVARIABLE_MARK: char = '#';
+
+const TAG_SPECIAL: &str = r"<>\";
+
+fn unescape(raw: &str) -> String {
+    let mut tag = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => tag.extend(chars.next()),
+            _ => tag.push(c),
+        }
+    }
+    tag
 }
 
-pub fn parse_stream_unit(input: &str) -> IResult<&str, StreamUnit> {
-    alt((
-        parse_space,
-        parse_format,
-        parse_basic_lu,
-        parse_joined_lu,
-        parse_chunk,
-    ))(input)
+pub fn make_sub_lu(flag: &str, mut ling_form: String, tags: Vec<&str>) -> SubLU {
+    let invariable = match ling_form.find(INVARIABLE_MARK) {
+        Some(pos) if pos > 0 => {
+            let invariable = ling_form[pos + INVARIABLE_MARK.len_utf8()..].to_string();
+            ling_form.truncate(pos);
+            Some(invariable)
+        }
+        _ => None,
+    };
+    SubLU {
+        invariable,
+        ..make_literal_sub_lu(flag, ling_form, tags)
+    }
 }
 
-pub fn parse_stream(input: &str) -> IResult<&str, Vec<StreamUnit>> {
-    let mut parse = many0(parse_stream_unit);
-    parse(input)
+/// Like `make_sub_lu`, but a `#` in `ling_form` is kept as it is.
+fn make_literal_sub_lu(flag: &str, ling_form: String, mut tags: Vec<&str>) -> SubLU {
+    let joins_next = tags.last() == Some(&JOIN_TAG);
+    if joins_next {
+        tags.pop();
+    }
+    let raw_tag_spans = if tags.iter().any(|tag| tag.contains('\\')) {
+        Some(tags.iter().map(|tag| String::from(*tag)).collect())
+    } else {
+        None
+    };
+    SubLU {
+        ling_form,
+        tags: tags.iter().map(|tag| unescape(tag)).collect(),
+        flags: make_flags(flag),
+        joins_next,
+        invariable: None,
+        weight: None,
+        raw_tag_spans,
+        compound_boundaries: vec![],
+        gen_failed: false,
+        delimited_chunk: false,
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use slurp;
+const LING_FORM_SPECIAL: &str = r#"^$@*/<>{}\[]+"#;
 
-    #[test]
+const LING_FORM_ESCAPABLE: &str = r#"^$@*/<>{}\[]+ "#;
+
+const WEIGHT_MARK: char = ':';
+
+const GEN_FAILURE_MARK: &str = "+?";
+
+fn with_option_chars(chars: &str, options: &ParserOptions) -> String {
+    let mut chars = String::from(chars);
+    chars.push(options.chunk_open);
+    chars.push(options.chunk_close);
+    if options.weights {
+        chars.push(WEIGHT_MARK);
+    }
+    chars
+}
+
+fn parse_flags(input: &str) -> IResult<&str, &str> {
+    take_while(|c| FLAG_SYMBOLS.contains(c))(input)
+}
+
+fn with_weight(mut sub_lu: SubLU, weight: Option<&str>) -> SubLU {
+    sub_lu.weight = weight.map(String::from);
+    sub_lu
+}
+
+#[derive(Clone, Copy)]
+enum Abort {
+    TooManyUnits,
+    ChunkTooDeep,
+    UnitTooLarge,
+    EmptyTag,
+}
+
+struct Parser<'o> {
+    options: &'o ParserOptions,
+    lenient: bool,
+    ling_form_special: String,
+    ling_form_escapable: String,
+    rejected_tag: RefCell<Option<(String, usize)>>,
+    recovered_separators: RefCell<Vec<usize>>,
+    aborted: Cell<Option<(Abort, usize)>>,
+    chunk_depth: Cell<usize>,
+    unit_count: Cell<usize>,
+    window_slack: Cell<usize>,
+}
+
+impl<'o> Parser<'o> {
+    fn new(options: &'o ParserOptions) -> Self {
+        let mut ling_form_escapable = with_option_chars(LING_FORM_ESCAPABLE, options);
+        if options.quoted_lemmas {
+            // So that a lemma can start with a `"` without being quoted.
+            ling_form_escapable.push('"');
+        }
+        Parser {
+            options,
+            lenient: options.lenient,
+            ling_form_special: with_option_chars(
+                &LING_FORM_SPECIAL.replace(['{', '}'], ""),
+                options,
+            ),
+            ling_form_escapable,
+            rejected_tag: RefCell::new(None),
+            recovered_separators: RefCell::new(vec![]),
+            aborted: Cell::new(None),
+            chunk_depth: Cell::new(0),
+            unit_count: Cell::new(0),
+            window_slack: Cell::new(0),
+        }
+    }
+
+    fn error(&self, input_len: usize) -> ParseError {
+        if let Some((abort, rest_len)) = self.aborted.get() {
+            let offset = input_len - rest_len;
+            return match abort {
+                Abort::TooManyUnits => ParseError::TooManyUnits { offset },
+                Abort::ChunkTooDeep => ParseError::ChunkTooDeep { offset },
+                Abort::UnitTooLarge => ParseError::UnitTooLarge { offset },
+                Abort::EmptyTag => ParseError::EmptyTag { offset },
+            };
+        }
+        match self.rejected_tag.borrow().as_ref() {
+            Some((tag, rest_len)) => ParseError::UnknownTag {
+                tag: tag.clone(),
+                offset: input_len - rest_len,
+            },
+            None => ParseError::Syntax { offset: 0 },
+        }
+    }
+
+    /// Like `error`, but a plain syntax error is put at `rest`, where the
+    /// failed unit starts, and the recorded reason is cleared so that
+    /// parsing can go on.
+    fn take_error(&self, input_len: usize, rest: &str) -> ParseError {
+        let error = match self.error(input_len) {
+            ParseError::Syntax { .. } => ParseError::Syntax {
+                offset: input_len - rest.len(),
+            },
+            error => error,
+        };
+        self.aborted.set(None);
+        self.rejected_tag.replace(None);
+        error
+    }
+
+    /// The length of the whole input left from `input` on, which reaches
+    /// past `input` while a unit is parsed within `max_unit_bytes`.
+    fn rest_len(&self, input: &str) -> usize {
+        input.len() + self.window_slack.get()
+    }
+
+    /// Remembers why parsing stopped and fails hard, so `alt` does not go
+    /// on to try the other branches.
+    fn abort<'a, O>(&self, abort: Abort, input: &'a str) -> IResult<&'a str, O> {
+        self.aborted.set(Some((abort, self.rest_len(input))));
+        let kind = match abort {
+            Abort::EmptyTag => nom::error::ErrorKind::Verify,
+            _ => nom::error::ErrorKind::TooLarge,
+        };
+        Err(nom::Err::Failure(nom::error::Error::new(input, kind)))
+    }
+
+    fn tag<'a>(&self, input: &'a str) -> IResult<&'a str, &'a str> {
+        if input.starts_with("<>") {
+            return self.abort(Abort::EmptyTag, input);
+        }
+        let tag_body = escaped(is_not(TAG_SPECIAL), '\\', anychar);
+        let mut parse = delimited(
+            tag("<"),
+            verify(tag_body, |t: &str| !t.is_empty()),
+            tag(">"),
+        );
+        let (i, t) = parse(input)?;
+        if let Some(validator) = &self.options.tag_validator {
+            if t != JOIN_TAG && !validator(&unescape(t)) {
+                *self.rejected_tag.borrow_mut() = Some((String::from(t), self.rest_len(input)));
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Verify,
+                )));
+            }
+        }
+        Ok((i, t))
+    }
+
+    /// In lenient mode a blank after the tags, where `/` was expected, is
+    /// taken as the separator and remembered as a warning.
+    fn analysis_separator<'a>(&self, input: &'a str) -> IResult<&'a str, &'a str> {
+        if !self.lenient {
+            return tag("/")(input);
+        }
+        let (i, separator) = alt((tag("/"), space1))(input)?;
+        if separator != "/" {
+            self.recovered_separators
+                .borrow_mut()
+                .push(self.rest_len(input));
+        }
+        Ok((i, separator))
+    }
+
+    fn warnings(&self, input_len: usize) -> Vec<ParseWarning> {
+        let mut rest_lens = self.recovered_separators.borrow().clone();
+        rest_lens.sort_unstable_by(|a, b| b.cmp(a));
+        rest_lens
+            .into_iter()
+            .map(|rest_len| ParseWarning::MissingAnalysisSeparator {
+                offset: input_len - rest_len,
+            })
+            .collect()
+    }
+
+    /// Runs `parse`, and when it fails forgets what it recorded on the way:
+    /// separators recovered in lenient mode and units counted against
+    /// `max_units`, so a branch that is not taken leaves nothing behind.
+    fn backtracking<'a, O, F>(&self, input: &'a str, parse: F) -> IResult<&'a str, O>
+    where
+        F: FnOnce(&'a str) -> IResult<&'a str, O>,
+    {
+        let recovered = self.recovered_separators.borrow().len();
+        let unit_count = self.unit_count.get();
+        let res = parse(input);
+        if res.is_err() {
+            self.recovered_separators.borrow_mut().truncate(recovered);
+            self.unit_count.set(unit_count);
+        }
+        res
+    }
+
+    fn basic_lu<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        self.backtracking(input, |i| self.basic_lu_unchecked(i))
+    }
+
+    fn basic_lu_unchecked<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        if self.options.keep_surface {
+            if let Ok(res) = self.backtracking(input, |i| self.surface_lu(i)) {
+                return Ok(res);
+            }
+        }
+        if self.options.compound_surface {
+            let parse_analyses = pair(
+                |i| self.surface_sub_lu(i),
+                many0(preceded(|i| self.analysis_separator(i), |i| self.sub_lu(i))),
+            );
+            let mut parse = delimited(tag("^"), parse_analyses, tag("$"));
+            return parse(input).map(|(i, (surface, mut analyses))| {
+                analyses.insert(0, surface);
+                (i, StreamUnit::lexical_unit(analyses))
+            });
+        }
+        let parse_analyses = separated_list0(|i| self.analysis_separator(i), |i| self.sub_lu(i));
+        let mut parse = delimited(tag("^"), parse_analyses, tag("$"));
+        let res = parse(input);
+        res.map(|(i, o)| (i, StreamUnit::lexical_unit(o)))
+    }
+
+    /// `^surface/analysis/...$`, with the text before the first `/` kept
+    /// as it was written, less its escapes.
+    fn surface_lu<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        let parse_surface = terminated(recognize(|i| self.sub_lu(i)), |i| {
+            self.analysis_separator(i)
+        });
+        let parse_analyses = pair(
+            parse_surface,
+            separated_list0(|i| self.analysis_separator(i), |i| self.sub_lu(i)),
+        );
+        let mut parse = delimited(tag("^"), parse_analyses, tag("$"));
+        parse(input).map(|(i, (surface, analyses))| {
+            let lu = LexicalUnit {
+                readings: analyses,
+                wordbound: None,
+                surface: Some(unescape(surface)),
+            };
+            (i, StreamUnit::LexicalUnit(lu))
+        })
+    }
+
+    fn compound_sub_lu<'a>(&self, input: &'a str) -> IResult<&'a str, SubLU> {
+        let mut parse = tuple((
+            parse_flags,
+            separated_list1(char('+'), |i| self.ling_form(i)),
+            many0(|i| self.tag(i)),
+            |i| self.weight(i),
+        ));
+        parse(input).map(|(i, (flag, parts, tags, weight))| {
+            let mut boundaries = vec![];
+            for part in &parts[..parts.len() - 1] {
+                boundaries.push(boundaries.last().unwrap_or(&0) + part.len());
+            }
+            let mut sub_lu = with_weight(make_sub_lu(flag, parts.concat(), tags), weight);
+            boundaries.retain(|&boundary| boundary < sub_lu.ling_form.len());
+            sub_lu.compound_boundaries = boundaries;
+            (i, sub_lu)
+        })
+    }
+
+    fn surface_sub_lu<'a>(&self, input: &'a str) -> IResult<&'a str, SubLU> {
+        alt((|i| self.compound_sub_lu(i), |i| self.sub_lu(i)))(input)
+    }
+
+    fn ling_form<'a>(&self, input: &'a str) -> IResult<&'a str, String> {
+        let (i, ling_form) = self.ling_form_text(input)?;
+        #[cfg(feature = "unicode-normalization")]
+        if self.options.normalize_nfc {
+            return Ok((i, ling_form.nfc().collect()));
+        }
+        Ok((i, ling_form))
+    }
+
+    fn ling_form_text<'a>(&self, input: &'a str) -> IResult<&'a str, String> {
+        let ling_form_inner_parse = is_not(self.ling_form_special.as_str());
+        let mut ling_form_escape_parse = escaped_transform(
+            ling_form_inner_parse,
+            '\\',
+            one_of(self.ling_form_escapable.as_str()),
+        );
+        ling_form_escape_parse(input)
+    }
+
+    fn sub_lu_basic<'a>(&self, input: &'a str) -> IResult<&'a str, SubLU> {
+        let (after_flags, _) = parse_flags(input)?;
+        if self.options.quoted_lemmas && after_flags.starts_with('"') {
+            return self.quoted_sub_lu(input);
+        }
+        let mut parse = tuple((
+            parse_flags,
+            |i| self.ling_form(i),
+            many0(|i| self.tag(i)),
+            |i| self.weight(i),
+        ));
+        parse(input).map(|(i, (flag, ling_form, tags, weight))| {
+            (i, with_weight(make_sub_lu(flag, ling_form, tags), weight))
+        })
+    }
+
+    /// A `"`-quoted lemma is taken literally: no escapes, no `#`
+    /// invariable part and no normalization.
+    fn quoted_sub_lu<'a>(&self, input: &'a str) -> IResult<&'a str, SubLU> {
+        let quoted = delimited(char('"'), take_while(|c| c != '"'), char('"'));
+        let mut parse = tuple((parse_flags, quoted, many0(|i| self.tag(i)), |i| {
+            self.weight(i)
+        }));
+        parse(input).map(|(i, (flag, ling_form, tags, weight))| {
+            let sub_lu = make_literal_sub_lu(flag, String::from(ling_form), tags);
+            (i, with_weight(sub_lu, weight))
+        })
+    }
+
+    fn sub_lu_without_ling_form<'a>(&self, input: &'a str) -> IResult<&'a str, SubLU> {
+        let mut parse = tuple((parse_flags, many0(|i| self.tag(i)), |i| self.weight(i)));
+        parse(input).map(|(i, (flag, tags, weight))| {
+            (
+                i,
+                with_weight(make_sub_lu(flag, String::from(""), tags), weight),
+            )
+        })
+    }
+
+    fn weight<'a>(&self, input: &'a str) -> IResult<&'a str, Option<&'a str>> {
+        if !self.options.weights {
+            return Ok((input, None));
+        }
+        opt(preceded(char(WEIGHT_MARK), recognize_float))(input)
+    }
+
+    fn sub_lu<'a>(&self, input: &'a str) -> IResult<&'a str, SubLU> {
+        let (i, mut sub_lu) = alt((
+            |i| self.sub_lu_basic(i),
+            |i| self.sub_lu_without_ling_form(i),
+        ))(input)?;
+        if !self.options.gen_failure_marker {
+            return Ok((i, sub_lu));
+        }
+        let (i, marker) = opt(terminated(tag(GEN_FAILURE_MARK), peek(one_of("/$+"))))(i)?;
+        sub_lu.gen_failed = marker.is_some();
+        Ok((i, sub_lu))
+    }
+
+    fn joined_lu<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        self.backtracking(input, |i| self.joined_lu_unchecked(i))
+    }
+
+    fn joined_lu_unchecked<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        if self.options.compound_surface {
+            let parse_surface = terminated(|i| self.surface_sub_lu(i), peek(one_of("/$")));
+            let parse_first = alt((
+                map(parse_surface, |surface| vec![surface]),
+                separated_list0(tag("+"), |i| self.sub_lu(i)),
+            ));
+            let parse_rest = many0(preceded(
+                |i| self.analysis_separator(i),
+                separated_list0(tag("+"), |i| self.sub_lu(i)),
+            ));
+            let mut parse = delimited(tag("^"), pair(parse_first, parse_rest), tag("$"));
+            return parse(input).map(|(i, (first, mut analyses))| {
+                analyses.insert(0, first);
+                (i, StreamUnit::JoinedLexicalUnit(analyses))
+            });
+        }
+        let parse_sub_lus = separated_list0(tag("+"), |i| self.sub_lu(i));
+        let parse_analyses = separated_list0(|i| self.analysis_separator(i), parse_sub_lus);
+        let mut parse = delimited(tag("^"), parse_analyses, tag("$"));
+        let res = parse(input);
+        res.map(|(i, o)| (i, StreamUnit::JoinedLexicalUnit(o)))
+    }
+
+    fn wordbound_lu<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        let parse_wordbound = delimited(tag("[["), is_not(r#"[]"#), tag("]]"));
+        let (i, (wordbound, unit)) = pair(parse_wordbound, |i| self.basic_lu(i))(input)?;
+        match unit {
+            StreamUnit::LexicalUnit(mut lu) => {
+                lu.wordbound = Some(String::from(wordbound));
+                Ok((i, StreamUnit::LexicalUnit(lu)))
+            }
+            unit => Ok((i, unit)),
+        }
+    }
+
+    fn lu_or_space_or_format<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        alt((
+            |i| self.wordbound_lu(i),
+            parse_superblank,
+            parse_format,
+            |i| self.basic_lu(i),
+            |i| self.joined_lu(i),
+            parse_space,
+            |i| self.chunk(i),
+        ))(input)
+    }
+
+    /// Children of a chunk that failed to close are not kept.
+    fn chunk<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        self.backtracking(input, |i| self.chunk_unchecked(i))
+    }
+
+    fn chunk_unchecked<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        let parse_children = delimited(
+            char(self.options.chunk_open),
+            |i| self.chunk_children(i),
+            char(self.options.chunk_close),
+        );
+        let (i, open) = opt(tag("^"))(input)?;
+        let (i, (mut head, children)) = pair(|i| self.sub_lu(i), parse_children)(i)?;
+        let (i, _) = match open {
+            Some(_) if self.options.chunk_trailing_dollar => tag("$")(i)?,
+            _ => (i, ""),
+        };
+        head.delimited_chunk = open.is_some();
+        Ok((i, StreamUnit::Chunk(head, children)))
+    }
+
+    fn chunk_children<'a>(&self, input: &'a str) -> IResult<&'a str, Vec<StreamUnit>> {
+        let depth = self.chunk_depth.get() + 1;
+        if self.options.max_chunk_depth.is_some_and(|max| depth > max) {
+            return self.abort(Abort::ChunkTooDeep, input);
+        }
+        self.chunk_depth.set(depth);
+        let res = self.units(input, |i| self.lu_or_space_or_format(i));
+        self.chunk_depth.set(depth - 1);
+        res
+    }
+
+    /// Tells a unit cut short by the `max_unit_bytes` window from one that
+    /// does not parse at all, by parsing `input` in full: only a unit that
+    /// parses, or holds a unit that is too large itself, is too large.
+    fn oversized_unit<'a, F>(&self, input: &'a str, parse_unit: &F) -> IResult<&'a str, StreamUnit>
+    where
+        F: Fn(&'a str) -> IResult<&'a str, StreamUnit>,
+    {
+        let unit_count = self.unit_count.get();
+        let recovered = self.recovered_separators.borrow().len();
+        let res = parse_unit(input);
+        self.unit_count.set(unit_count);
+        self.recovered_separators.borrow_mut().truncate(recovered);
+        match res {
+            Ok(_) => self.abort(Abort::UnitTooLarge, input),
+            Err(nom::Err::Failure(_))
+                if matches!(self.aborted.get(), Some((Abort::UnitTooLarge, _))) =>
+            {
+                self.abort(Abort::UnitTooLarge, input)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Parses one unit with `parse_unit`, looking no further into `input`
+    /// than `max_unit_bytes`, and counts it against `max_units`.
+    fn limited_unit<'a, F>(&self, input: &'a str, parse_unit: &F) -> IResult<&'a str, StreamUnit>
+    where
+        F: Fn(&'a str) -> IResult<&'a str, StreamUnit>,
+    {
+        let (i, unit) = match self.options.max_unit_bytes {
+            Some(max) if input.len() > max => {
+                let end = (0..=max + 1)
+                    .rev()
+                    .find(|&end| input.is_char_boundary(end))
+                    .unwrap_or(0);
+                let (window, cut) = input.split_at(end);
+                let slack = self.window_slack.get();
+                self.window_slack.set(slack + cut.len());
+                let res = parse_unit(window);
+                self.window_slack.set(slack);
+                match res {
+                    Ok((i, _)) if window.len() - i.len() > max => {
+                        return self.abort(Abort::UnitTooLarge, input)
+                    }
+                    Ok((i, unit)) => (&input[window.len() - i.len()..], unit),
+                    Err(nom::Err::Error(_)) => return self.oversized_unit(input, parse_unit),
+                    Err(e) => return Err(e),
+                }
+            }
+            _ => parse_unit(input)?,
+        };
+        let count = self.unit_count.get() + 1;
+        if self.options.max_units.is_some_and(|max| count > max) {
+            return self.abort(Abort::TooManyUnits, input);
+        }
+        self.unit_count.set(count);
+        Ok((i, unit))
+    }
+
+    /// Parses units with `parse_unit` until there is none left, each one
+    /// within the limits of `limited_unit`.
+    fn units<'a, F>(&self, input: &'a str, parse_unit: F) -> IResult<&'a str, Vec<StreamUnit>>
+    where
+        F: Fn(&'a str) -> IResult<&'a str, StreamUnit>,
+    {
+        let mut units = vec![];
+        let mut rest = input;
+        loop {
+            let (i, unit) = match self.limited_unit(rest, &parse_unit) {
+                Ok((i, _)) if i.len() == rest.len() => break,
+                Ok(res) => res,
+                Err(nom::Err::Error(_)) => break,
+                Err(e) => return Err(e),
+            };
+            units.push(unit);
+            rest = i;
+        }
+        Ok((rest, units))
+    }
+
+    fn stream_unit<'a>(&self, input: &'a str) -> IResult<&'a str, StreamUnit> {
+        alt((
+            parse_space,
+            parse_null_flush,
+            |i| self.wordbound_lu(i),
+            parse_superblank,
+            parse_format,
+            |i| self.basic_lu(i),
+            |i| self.joined_lu(i),
+            |i| self.chunk(i),
+        ))(input)
+    }
+
+    fn stream<'a>(&self, input: &'a str) -> IResult<&'a str, Vec<StreamUnit>> {
+        self.units(input, |i| self.stream_unit(i))
+    }
+
+    fn stream_with_preamble<'a>(&self, input: &'a str) -> IResult<&'a str, Vec<StreamUnit>> {
+        let (input, preamble) = if self.options.preamble {
+            opt(parse_preamble)(input)?
+        } else {
+            (input, None)
+        };
+        let (input, mut stream) = self.stream(input)?;
+        if let Some(preamble) = preamble {
+            stream.insert(0, preamble);
+        }
+        Ok((input, stream))
+    }
+}
+
+pub fn parse_tag(input: &str) -> IResult<&str, &str> {
+    Parser::new(&ParserOptions::default()).tag(input)
+}
+
+/// An empty unit `^$` is a single `SubLU` with an empty ling form and no
+/// tags, so it serializes back to `^$`.
+pub fn parse_basic_lu(input: &str) -> IResult<&str, StreamUnit> {
+    Parser::new(&ParserOptions::default()).basic_lu(input)
+}
+
+/// `^...$` with a `[[...]]` wordbound blank right before it, kept on the
+/// resulting `LexicalUnit`.
+pub fn parse_wordbound_lu(input: &str) -> IResult<&str, StreamUnit> {
+    Parser::new(&ParserOptions::default()).wordbound_lu(input)
+}
+
+pub fn parse_sub_lu_basic(input: &str) -> IResult<&str, SubLU> {
+    Parser::new(&ParserOptions::default()).sub_lu_basic(input)
+}
+
+pub fn parse_sub_lu_without_ling_form(input: &str) -> IResult<&str, SubLU> {
+    Parser::new(&ParserOptions::default()).sub_lu_without_ling_form(input)
+}
+
+pub fn parse_sub_lu(input: &str) -> IResult<&str, SubLU> {
+    Parser::new(&ParserOptions::default()).sub_lu(input)
+}
+
+pub fn parse_sub_lu_ref(input: &str) -> IResult<&str, SubLuRef<'_>> {
+    let raw_ling_form = escaped(is_not(LING_FORM_SPECIAL), '\\', one_of(LING_FORM_ESCAPABLE));
+    let tag_body = escaped(is_not(TAG_SPECIAL), '\\', anychar);
+    let parse_tag = delimited(
+        tag("<"),
+        verify(tag_body, |t: &str| !t.is_empty()),
+        tag(">"),
+    );
+    let mut parse = tuple((parse_flags, opt(raw_ling_form), many0(parse_tag)));
+    parse(input).map(|(i, (flags, raw, tags))| {
+        let raw = raw.unwrap_or("");
+        let ling_form = if raw.contains('\\') {
+            Cow::Owned(unescape(raw))
+        } else {
+            Cow::Borrowed(raw)
+        };
+        let flags = make_flags(flags);
+        (
+            i,
+            SubLuRef {
+                ling_form,
+                tags,
+                flags,
+            },
+        )
+    })
+}
+
+pub fn parse_basic_lu_ref(input: &str) -> IResult<&str, Vec<SubLuRef<'_>>> {
+    let parse_analyses = separated_list0(tag("/"), parse_sub_lu_ref);
+    delimited(tag("^"), parse_analyses, tag("$"))(input)
+}
+
+/// Empty sub-units around a `+` (as in `^a+$` or `^a++b$`) are preserved as
+/// `SubLU`s with an empty ling form, so degenerate joins still round-trip.
+pub fn parse_joined_lu(input: &str) -> IResult<&str, StreamUnit> {
+    Parser::new(&ParserOptions::default()).joined_lu(input)
+}
+
+pub fn parse_lu_or_space_or_format(input: &str) -> IResult<&str, StreamUnit> {
+    Parser::new(&ParserOptions::default()).lu_or_space_or_format(input)
+}
+
+pub fn parse_chunk(input: &str) -> IResult<&str, StreamUnit> {
+    Parser::new(&ParserOptions::default()).chunk(input)
+}
+
+pub fn parse_format(input: &str) -> IResult<&str, StreamUnit> {
+    let mut parse = delimited(tag("["), is_not(r#"[]"#), tag("]"));
+    let res = parse(input);
+    res.map(|(i, o)| (i, StreamUnit::Format(String::from(o))))
+}
+
+pub fn parse_superblank(input: &str) -> IResult<&str, StreamUnit> {
+    let mut parse = delimited(tag("[["), is_not(r#"[]"#), tag("]]"));
+    let res = parse(input);
+    res.map(|(i, o)| (i, StreamUnit::Superblank(String::from(o))))
+}
+
+pub fn parse_null_flush(input: &str) -> IResult<&str, StreamUnit> {
+    char('\0')(input).map(|(i, _)| (i, StreamUnit::NullFlush))
+}
+
+pub fn parse_space(input: &str) -> IResult<&str, StreamUnit> {
+    alt((space1, tag("\n"), tag("\r\n"), tag("\r")))(input)
+        .map(|(i, o)| (i, StreamUnit::Space(String::from(o))))
+}
+
+pub fn parse_stream_unit(input: &str) -> IResult<&str, StreamUnit> {
+    Parser::new(&ParserOptions::default()).stream_unit(input)
+}
+
+pub fn parse_stream(input: &str) -> IResult<&str, Vec<StreamUnit>> {
+    Parser::new(&ParserOptions::default()).stream(input)
+}
+
+pub fn parse_preamble(input: &str) -> IResult<&str, StreamUnit> {
+    is_not("^[")(input).map(|(i, o)| (i, StreamUnit::Preamble(String::from(o))))
+}
+
+pub fn parse_stream_with_options<'a>(
+    input: &'a str,
+    options: &ParserOptions,
+) -> IResult<&'a str, Vec<StreamUnit>> {
+    Parser::new(options).stream_with_preamble(input)
+}
+
+pub fn parse(input: &str, options: &ParserOptions) -> Result<Stream, ParseError> {
+    parse_with_warnings(input, options).map(|(stream, _)| stream)
+}
+
+/// Skips a leading BOM and then a `#!` line, returning the line without
+/// its newline.
+fn split_shebang(input: &str) -> (&str, Option<&str>) {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    if !input.starts_with("#!") {
+        return (input, None);
+    }
+    match input.find('\n') {
+        Some(end) => (&input[end + 1..], Some(&input[..end])),
+        None => ("", Some(input)),
+    }
+}
+
+pub fn parse_stream_strict(input: &str) -> Result<Vec<StreamUnit>, ParseError> {
+    parse(input, &ParserOptions::default()).map(Stream::into_units)
+}
+
+pub fn parse_with_warnings(
+    input: &str,
+    options: &ParserOptions,
+) -> Result<(Stream, Vec<ParseWarning>), ParseError> {
+    let parser = Parser::new(options);
+    let (body, shebang) = if options.shebang {
+        split_shebang(input)
+    } else {
+        (input, None)
+    };
+    let (mut rest, mut stream) = parser
+        .stream_with_preamble(body)
+        .map_err(|_| parser.error(input.len()))?;
+    while !rest.is_empty() {
+        let offset = input.len() - rest.len();
+        if is_unterminated(rest, '[', ']') {
+            return Err(ParseError::UnterminatedFormat { offset });
+        }
+        if is_unterminated(rest, '^', '$') {
+            return Err(ParseError::UnterminatedLexicalUnit { offset });
+        }
+        if !rest.starts_with('$') {
+            return Err(ParseError::Syntax { offset });
+        }
+        if !options.lenient {
+            return Err(ParseError::UnexpectedDollar { offset });
+        }
+        stream.push(StreamUnit::Unknown(String::from("$")));
+        let (i, more) = parser
+            .stream(&rest[1..])
+            .map_err(|_| parser.error(input.len()))?;
+        stream.extend(more);
+        rest = i;
+    }
+    Ok((
+        Stream {
+            units: stream,
+            retained: Retained {
+                shebang: shebang.map(String::from),
+                ..Default::default()
+            },
+        },
+        parser.warnings(input.len()),
+    ))
+}
+
+/// On failure, the units parsed before the first unit that failed, and the
+/// input from that unit on.
+pub fn parse_stream_debug(input: &str) -> Result<Vec<StreamUnit>, (Vec<StreamUnit>, String)> {
+    let options = ParserOptions::default();
+    let parser = Parser::new(&options);
+    let mut stream = vec![];
+    let mut rest = input;
+    while !rest.is_empty() {
+        match parser.stream_unit(rest) {
+            Ok((i, unit)) => {
+                stream.push(unit);
+                rest = i;
+            }
+            Err(_) => return Err((stream, String::from(rest))),
+        }
+    }
+    Ok(stream)
+}
+
+pub fn parse_stream_with_progress<F: FnMut(usize)>(
+    input: &str,
+    every_bytes: usize,
+    mut cb: F,
+) -> Result<Vec<StreamUnit>, ParseError> {
+    let options = ParserOptions::default();
+    let parser = Parser::new(&options);
+    let every_bytes = every_bytes.max(1);
+    let mut next_report = every_bytes;
+    let mut stream = vec![];
+    let mut rest = input;
+    while !rest.is_empty() {
+        let (i, unit) = parser
+            .stream_unit(rest)
+            .map_err(|_| parser.take_error(input.len(), rest))?;
+        stream.push(unit);
+        rest = i;
+        let consumed = input.len() - rest.len();
+        if consumed >= next_report {
+            cb(consumed);
+            next_report = (consumed / every_bytes + 1) * every_bytes;
+        }
+    }
+    Ok(stream)
+}
+
+fn is_unterminated(rest: &str, open: char, close: char) -> bool {
+    let mut chars = rest.chars();
+    if chars.next() != Some(open) {
+        return false;
+    }
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == close {
+            return false;
+        }
+    }
+    true
+}
+
+/// Like `parse_stream`, but a trailing format or lexical unit that is cut
+/// off by the end of the input yields `Incomplete` so more can be read.
+pub fn parse_stream_partial(input: &str) -> IResult<&str, Vec<StreamUnit>> {
+    let (rest, stream) = parse_stream(input)?;
+    if is_unterminated(rest, '[', ']') || is_unterminated(rest, '^', '$') {
+        return Err(nom::Err::Incomplete(nom::Needed::Unknown));
+    }
+    Ok((rest, stream))
+}
+
+const SNIFF_LEN: usize = 512;
+
+pub fn looks_like_stream(input: &str) -> bool {
+    let prefix = &input.as_bytes()[..input.len().min(SNIFF_LEN)];
+    let mut bytes = prefix.iter();
+    loop {
+        match bytes.next() {
+            Some(b'^') => break,
+            Some(b'[') => {
+                if !bytes.any(|b| *b == b']') {
+                    return false;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() || *b == 0 => {}
+            _ => return false,
+        }
+    }
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => {
+                bytes.next();
+            }
+            b'$' | b'<' | b'/' => return true,
+            b'^' => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn split_documents(input: &str) -> Vec<&str> {
+    let mut documents = vec![];
+    let mut rest = input;
+    while let Some(start) = rest.find("\n\n") {
+        let end = rest[start..]
+            .find(|c| c != '\n')
+            .map_or(rest.len(), |len| start + len);
+        documents.push(&rest[..start]);
+        rest = &rest[end..];
+    }
+    documents.push(rest);
+    documents.retain(|document| !document.is_empty());
+    documents
+}
+
+/// Splits on blank lines (two or more newlines) and parses each document on
+/// its own, so an error in one does not affect the others.
+pub fn parse_documents(input: &str) -> Vec<Result<Vec<StreamUnit>, ParseError>> {
+    let options = ParserOptions::default();
+    split_documents(input)
+        .into_iter()
+        .map(|document| parse(document, &options).map(Stream::into_units))
+        .collect()
+}
+
+fn is_recovery_point(c: char) -> bool {
+    c == '^' || c == '[' || c.is_whitespace()
+}
+
+/// Parses in lenient mode, skipping past each unit that fails to the next
+/// `^`, `[` or blank and recording why it failed.
+pub fn parse_recovering(input: &str) -> (Vec<StreamUnit>, Vec<ParseError>) {
+    parse_recovering_with_options(input, &ParserOptions::default())
+}
+
+/// Like `parse_recovering`, with tag validation and limits taken from
+/// `options`. Parsing is lenient even when `options.lenient` is not set.
+pub fn parse_recovering_with_options(
+    input: &str,
+    options: &ParserOptions,
+) -> (Vec<StreamUnit>, Vec<ParseError>) {
+    let mut parser = Parser::new(options);
+    parser.lenient = true;
+    let mut stream = vec![];
+    let mut errors = vec![];
+    let mut rest = input;
+    while !rest.is_empty() {
+        match parser.limited_unit(rest, &|i| parser.stream_unit(i)) {
+            Ok((i, unit)) => {
+                stream.push(unit);
+                rest = i;
+            }
+            Err(_) => {
+                errors.push(parser.take_error(input.len(), rest));
+                let skip = rest
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| is_recovery_point(*c))
+                    .map_or(rest.len(), |(pos, _)| pos);
+                rest = &rest[skip..];
+            }
+        }
+    }
+    (stream, errors)
+}
+
+fn log_token(sub_lu: &SubLU) -> String {
+    match sub_lu.tags.first() {
+        Some(pos) => format!("{}.{}", sub_lu.ling_form, pos),
+        None => sub_lu.ling_form.clone(),
+    }
+}
+
+fn log_unit(unit: &StreamUnit) -> Option<String> {
+    match unit {
+        StreamUnit::LexicalUnit(_) => unit.first_analysis().map(log_token),
+        StreamUnit::JoinedLexicalUnit(analyses) => {
+            joined_analyses(analyses).first().map(|sub_lus| {
+                sub_lus
+                    .iter()
+                    .map(log_token)
+                    .collect::<Vec<String>>()
+                    .join("+")
+            })
+        }
+        StreamUnit::Chunk(head, children) => {
+            Some(format!("{}{{{}}}", log_token(head), to_log_line(children)))
+        }
+        _ => None,
+    }
+}
+
+/// Each unit becomes `lemma.pos` from its first analysis, and each run of
+/// blanks between two of them a single space; formats are left out.
+pub fn to_log_line(stream: &[StreamUnit]) -> String {
+    let mut line = String::new();
+    let mut blank = false;
+    for unit in stream {
+        if let StreamUnit::Space(_) = unit {
+            blank = true;
+        } else if let Some(token) = log_unit(unit) {
+            if blank && !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+            blank = false;
+        }
+    }
+    line
+}
+
+fn push_surface(out: &mut String, stream: &[StreamUnit], joined: &mut bool) {
+    for unit in stream {
+        let sub_lus: &[SubLU] = match unit {
+            StreamUnit::LexicalUnit(LexicalUnit {
+                readings,
+                surface: Some(surface),
+                ..
+            }) => {
+                out.push_str(surface);
+                *joined = readings.first().is_some_and(SubLU::joins_next);
+                continue;
+            }
+            StreamUnit::LexicalUnit(analyses) => analyses
+                .first()
+                .map(std::slice::from_ref)
+                .unwrap_or_default(),
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                analyses.first().map(Vec::as_slice).unwrap_or_default()
+            }
+            StreamUnit::Chunk(_, children) => {
+                push_surface(out, children, joined);
+                continue;
+            }
+            StreamUnit::Space(space) => {
+                if !*joined {
+                    out.push_str(space);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        for sub_lu in sub_lus {
+            out.push_str(&sub_lu.ling_form);
+            if let Some(invariable) = &sub_lu.invariable {
+                out.push(' ');
+                out.push_str(invariable);
+            }
+        }
+        *joined = sub_lus.last().is_some_and(SubLU::joins_next);
+    }
+}
+
+pub fn surface_text(stream: &[StreamUnit]) -> String {
+    let mut out = String::new();
+    push_surface(&mut out, stream, &mut false);
+    out
+}
+
+pub fn parse_all(input: &str) -> Result<Vec<StreamUnit>, ParseError> {
+    let options = ParserOptions::default();
+    let parser = Parser::new(&options);
+    match parser.stream(input) {
+        Ok(("", stream)) => Ok(stream),
+        Ok((rest, _)) => Err(ParseError::Syntax {
+            offset: input.len() - rest.len(),
+        }),
+        Err(_) => Err(parser.error(input.len())),
+    }
+}
+
+const LEFTOVER_CHARS: usize = 16;
+
+/// Like `parse_all`, but input left over where parsing stopped is reported
+/// as `TrailingInput` with its first few characters.
+pub fn parse_stream_checked(input: &str) -> Result<Vec<StreamUnit>, ParseError> {
+    parse_all(input).map_err(|e| match e {
+        ParseError::Syntax { offset } => ParseError::TrailingInput {
+            offset,
+            leftover: input[offset..].chars().take(LEFTOVER_CHARS).collect(),
+        },
+        e => e,
+    })
+}
+
+impl std::str::FromStr for StreamUnit {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<StreamUnit, ParseError> {
+        let options = ParserOptions::default();
+        let parser = Parser::new(&options);
+        match parser.stream_unit(s) {
+            Ok(("", unit)) => Ok(unit),
+            Ok((rest, _)) => Err(ParseError::Syntax {
+                offset: s.len() - rest.len(),
+            }),
+            Err(_) => Err(parser.error(s.len())),
+        }
+    }
+}
+
+fn parse_segment(segment: io::Result<Vec<u8>>) -> Result<Vec<StreamUnit>, ParseError> {
+    let segment = segment?;
+    let segment = String::from_utf8(segment).map_err(|e| ParseError::InvalidUtf8 {
+        offset: e.utf8_error().valid_up_to(),
+    })?;
+    parse_all(&segment)
+}
+
+/// Each segment is read as text and its surface collected unit by unit, so
+/// no more than one unit is held parsed at a time.
+pub fn surface_text_from_reader<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<String, ParseError>> {
+    reader.split(b'\0').map(|segment| {
+        let segment = segment?;
+        let mut text = String::new();
+        let mut joined = false;
+        for_each_unit(segment.as_slice(), |unit| {
+            push_surface(&mut text, std::slice::from_ref(&unit), &mut joined);
+            Ok::<(), ParseError>(())
+        })?;
+        Ok(text)
+    })
+}
+
+fn apply_to_units<F: FnMut(&mut StreamUnit)>(stream: &mut [StreamUnit], f: &mut F) {
+    for unit in stream {
+        f(unit);
+        if let StreamUnit::Chunk(_, children) = unit {
+            apply_to_units(children, f);
+        }
+    }
+}
+
+/// Entry point for a pipeline stage: every unit, chunk children included,
+/// goes through `f` before being written back. Null flushes are kept.
+pub fn run_filter<F: FnMut(&mut StreamUnit)>(
+    mut input: impl BufRead,
+    mut output: impl Write,
+    mut f: F,
+) -> io::Result<()> {
+    let mut segment = vec![];
+    while input.read_until(b'\0', &mut segment)? > 0 {
+        let flush = segment.last() == Some(&b'\0');
+        if flush {
+            segment.pop();
+        }
+        let mut stream = parse_segment(Ok(std::mem::take(&mut segment)))?;
+        apply_to_units(&mut stream, &mut f);
+        output.write_all(serialize_stream(&stream).as_bytes())?;
+        if flush {
+            output.write_all(b"\0")?;
+        }
+    }
+    output.flush()
+}
+
+/// The frame is read as it arrives rather than allocated up front, so a
+/// bogus length prefix cannot make it allocate more than was sent.
+pub fn read_length_prefixed<R: Read>(reader: &mut R) -> io::Result<Vec<StreamUnit>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    let mut frame = vec![];
+    reader.take(u64::from(len)).read_to_end(&mut frame)?;
+    if frame.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(parse_segment(Ok(frame))?)
+}
+
+/// With `ensure_trailing_newline`, a `\n` is appended unless the stream
+/// already ends with one.
+pub fn write_stream<W: Write>(
+    writer: &mut W,
+    stream: &[StreamUnit],
+    ensure_trailing_newline: bool,
+) -> io::Result<()> {
+    let out = serialize_stream(stream);
+    writer.write_all(out.as_bytes())?;
+    if ensure_trailing_newline && !out.ends_with('\n') {
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+pub fn write_length_prefixed<W: Write>(writer: &mut W, stream: &[StreamUnit]) -> io::Result<()> {
+    let frame = serialize_stream(stream);
+    let len =
+        u32::try_from(frame.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(frame.as_bytes())
+}
+
+/// Parses `reader` one top-level unit at a time, so only the unit being
+/// read is held in memory.
+fn for_each_unit<R, E, F>(mut reader: R, mut f: F) -> Result<(), E>
+where
+    R: BufRead,
+    E: From<ParseError> + From<io::Error>,
+    F: FnMut(StreamUnit) -> Result<(), E>,
+{
+    let options = ParserOptions::default();
+    let parser = Parser::new(&options);
+    let mut buf = vec![];
+    let mut offset = 0;
+    let mut eof = false;
+    loop {
+        let text = match std::str::from_utf8(&buf) {
+            Ok(text) => text,
+            Err(e) if e.error_len().is_none() && !eof => {
+                std::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or_default()
+            }
+            Err(e) => {
+                let offset = offset + e.valid_up_to();
+                return Err(ParseError::InvalidUtf8 { offset }.into());
+            }
+        };
+        let mut rest = text;
+        while !rest.is_empty() {
+            match parser.stream_unit(rest) {
+                // A unit running to the end of the buffer may go on in
+                // the next read.
+                Ok((i, unit)) if eof || !i.is_empty() => {
+                    f(unit)?;
+                    rest = i;
+                }
+                Ok(_) | Err(nom::Err::Error(_)) if !eof => break,
+                Err(nom::Err::Failure(_)) => {
+                    return Err(parser.error(offset + text.len()).into());
+                }
+                _ => {
+                    let offset = offset + text.len() - rest.len();
+                    return Err(ParseError::Syntax { offset }.into());
+                }
+            }
+        }
+        let consumed = text.len() - rest.len();
+        buf.drain(..consumed);
+        offset += consumed;
+        if eof {
+            return Ok(());
+        }
+        let read = reader.fill_buf()?;
+        eof = read.is_empty();
+        buf.extend_from_slice(read);
+        let len = read.len();
+        reader.consume(len);
+    }
+}
+
+/// Writes each unit read as one line of JSON, in the form the `serde`
+/// derives give `StreamUnit`; blanks and formats only with `include_blanks`.
+#[cfg(feature = "serde")]
+pub fn export_ndjson<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    include_blanks: bool,
+) -> io::Result<()> {
+    for_each_unit(reader, |unit| {
+        if !include_blanks && !unit.is_lexical_unit() && !matches!(unit, StreamUnit::Chunk(..)) {
+            return Ok(());
+        }
+        serde_json::to_writer(&mut writer, &unit)?;
+        writer.write_all(b"\n")
+    })
+}
+
+/// Inverse of the flag prefixes accepted by the parser.
+pub fn flag_symbol(flag: &Flag) -> &'static str {
+    match flag {
+        Flag::Nothing => "",
+        Flag::Unanalyzed => "*",
+        Flag::Untranslated => "@",
+        Flag::UnableToGenerateOrStartOfInvariablePart => "#",
+    }
+}
+
+const fn special_bytes() -> [bool; 256] {
+    let mut table = [false; 256];
+    let special = LING_FORM_SPECIAL.as_bytes();
+    let mut i = 0;
+    while i < special.len() {
+        table[special[i] as usize] = true;
+        i += 1;
+    }
+    table
+}
+
+static IS_SPECIAL_BYTE: [bool; 256] = special_bytes();
+
+fn needs_escaping(ling_form: &str) -> bool {
+    ling_form.bytes().any(|b| IS_SPECIAL_BYTE[b as usize])
+}
+
+/// How a stream is written, following the `ParserOptions` that read it.
+#[derive(Clone, Copy)]
+struct Dialect {
+    chunk_open: char,
+    chunk_close: char,
+    chunk_trailing_dollar: bool,
+    quoted_lemmas: bool,
+}
+
+impl Dialect {
+    const DEFAULT: Dialect = Dialect {
+        chunk_open: '{',
+        chunk_close: '}',
+        chunk_trailing_dollar: true,
+        quoted_lemmas: false,
+    };
+
+    fn of(options: &ParserOptions) -> Dialect {
+        Dialect {
+            chunk_open: options.chunk_open,
+            chunk_close: options.chunk_close,
+            chunk_trailing_dollar: options.chunk_trailing_dollar,
+            quoted_lemmas: options.quoted_lemmas,
+        }
+    }
+
+    /// Besides the usual special characters, the chunk delimiters and,
+    /// with quoted lemmas, `"`.
+    fn escapes(&self, c: char) -> bool {
+        c == self.chunk_open || c == self.chunk_close || (self.quoted_lemmas && c == '"')
+    }
+
+    /// With quoted lemmas, a `#` that is not an invariable mark is kept by
+    /// quoting the lemma, which works unless it holds a `"` as well.
+    fn quotes(&self, sub_lu: &SubLU) -> bool {
+        self.quoted_lemmas
+            && sub_lu.invariable.is_none()
+            && sub_lu.compound_boundaries.is_empty()
+            && sub_lu.ling_form.contains(INVARIABLE_MARK)
+            && !sub_lu.ling_form.contains('"')
+    }
+}
+
+fn write_ling_form(out: &mut String, ling_form: &str, dialect: Dialect) {
+    if !needs_escaping(ling_form) && !ling_form.contains(|c| dialect.escapes(c)) {
+        out.push_str(ling_form);
+        return;
+    }
+    for c in ling_form.chars() {
+        if (c.is_ascii() && IS_SPECIAL_BYTE[c as usize]) || dialect.escapes(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+fn write_tag(out: &mut String, raw: &str) {
+    out.push('<');
+    out.push_str(raw);
+    out.push('>');
+}
+
+fn write_escaped_tag(out: &mut String, tag: &str) {
+    out.push('<');
+    for c in tag.chars() {
+        if TAG_SPECIAL.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('>');
+}
+
+fn write_sub_lu(out: &mut String, sub_lu: &SubLU, dialect: Dialect) {
+    for flag in &sub_lu.flags {
+        out.push_str(flag_symbol(flag));
+    }
+    if dialect.quotes(sub_lu) {
+        out.push('"');
+        out.push_str(&sub_lu.ling_form);
+        out.push('"');
+    } else {
+        for (i, part) in sub_lu.compound_parts().into_iter().enumerate() {
+            if i > 0 {
+                out.push('+');
+            }
+            write_ling_form(out, part, dialect);
+        }
+    }
+    if let Some(invariable) = &sub_lu.invariable {
+        out.push(INVARIABLE_MARK);
+        write_ling_form(out, invariable, dialect);
+    }
+    match &sub_lu.raw_tag_spans {
+        Some(spans) => spans.iter().for_each(|span| write_tag(out, span)),
+        None => sub_lu
+            .tags
+            .iter()
+            .for_each(|tag| write_escaped_tag(out, tag)),
+    }
+    if sub_lu.joins_next {
+        out.push('<');
+        out.push_str(JOIN_TAG);
+        out.push('>');
+    }
+    if let Some(weight) = &sub_lu.weight {
+        out.push(WEIGHT_MARK);
+        out.push_str(weight);
+    }
+    if sub_lu.gen_failed {
+        out.push_str(GEN_FAILURE_MARK);
+    }
+}
+
+fn write_analyses<T>(out: &mut String, analyses: &[T], write_analysis: impl Fn(&mut String, &T)) {
+    out.push('^');
+    for (i, analysis) in analyses.iter().enumerate() {
+        if i > 0 {
+            out.push('/');
+        }
+        write_analysis(out, analysis);
+    }
+    out.push('$');
+}
+
+fn write_joined_sub_lus(out: &mut String, sub_lus: &[SubLU], dialect: Dialect) {
+    for (i, sub_lu) in sub_lus.iter().enumerate() {
+        if i > 0 {
+            out.push('+');
+        }
+        write_sub_lu(out, sub_lu, dialect);
+    }
+}
+
+fn write_stream_unit(out: &mut String, unit: &StreamUnit) {
+    write_stream_unit_with(out, unit, Dialect::DEFAULT)
+}
+
+fn write_stream_unit_with(out: &mut String, unit: &StreamUnit, dialect: Dialect) {
+    match unit {
+        StreamUnit::LexicalUnit(analyses) => {
+            if let Some(wordbound) = analyses.wordbound() {
+                out.push_str("[[");
+                out.push_str(wordbound);
+                out.push_str("]]");
+            }
+            match &analyses.surface {
+                Some(surface) => {
+                    out.push('^');
+                    write_ling_form(out, surface, dialect);
+                    for analysis in analyses.iter() {
+                        out.push('/');
+                        write_sub_lu(out, analysis, dialect);
+                    }
+                    out.push('$');
+                }
+                None => write_analyses(out, analyses, |out, analysis| {
+                    write_sub_lu(out, analysis, dialect)
+                }),
+            }
+        }
+        StreamUnit::JoinedLexicalUnit(analyses) => write_analyses(out, analyses, |out, sub_lus| {
+            write_joined_sub_lus(out, sub_lus, dialect)
+        }),
+        StreamUnit::Chunk(head, children) => {
+            if head.delimited_chunk {
+                out.push('^');
+            }
+            write_sub_lu(out, head, dialect);
+            out.push(dialect.chunk_open);
+            for child in children {
+                write_stream_unit_with(out, child, dialect);
+            }
+            out.push(dialect.chunk_close);
+            if head.delimited_chunk && dialect.chunk_trailing_dollar {
+                out.push('$');
+            }
+        }
+        StreamUnit::Format(format) => {
+            out.push('[');
+            out.push_str(format);
+            out.push(']');
+        }
+        StreamUnit::NullFlush => out.push('\0'),
+        StreamUnit::Superblank(superblank) => {
+            out.push_str("[[");
+            out.push_str(superblank);
+            out.push_str("]]");
+        }
+        StreamUnit::Space(s) | StreamUnit::Preamble(s) | StreamUnit::Unknown(s) => out.push_str(s),
+    }
+}
+
+fn ling_form_len(ling_form: &str, len: fn(&str) -> usize) -> usize {
+    len(ling_form)
+        + ling_form
+            .bytes()
+            .filter(|b| IS_SPECIAL_BYTE[*b as usize])
+            .count()
+}
+
+fn tags_len(sub_lu: &SubLU, len: fn(&str) -> usize) -> usize {
+    match &sub_lu.raw_tag_spans {
+        Some(spans) => spans.iter().map(|span| len(span) + 2).sum(),
+        None => sub_lu
+            .tags
+            .iter()
+            .map(|tag| len(tag) + 2 + tag.matches(|c| TAG_SPECIAL.contains(c)).count())
+            .sum(),
+    }
+}
+
+fn sub_lu_len(sub_lu: &SubLU, len: fn(&str) -> usize) -> usize {
+    let join_len = if sub_lu.joins_next {
+        len(JOIN_TAG) + 2
+    } else {
+        0
+    };
+    let invariable_len = match &sub_lu.invariable {
+        Some(invariable) => 1 + ling_form_len(invariable, len),
+        None => 0,
+    };
+    let weight_len = sub_lu.weight.as_ref().map_or(0, |weight| 1 + len(weight));
+    sub_lu
+        .flags
+        .iter()
+        .map(|flag| len(flag_symbol(flag)))
+        .sum::<usize>()
+        + ling_form_len(&sub_lu.ling_form, len)
+        + sub_lu.compound_boundaries.len()
+        + invariable_len
+        + tags_len(sub_lu, len)
+        + join_len
+        + weight_len
+        + if sub_lu.gen_failed {
+            len(GEN_FAILURE_MARK)
+        } else {
+            0
+        }
+}
+
+fn analyses_len<T>(analyses: &[T], analysis_len: impl Fn(&T) -> usize) -> usize {
+    let separators = analyses.len().saturating_sub(1);
+    2 + separators + analyses.iter().map(analysis_len).sum::<usize>()
+}
+
+fn stream_unit_len(unit: &StreamUnit, len: fn(&str) -> usize) -> usize {
+    match unit {
+        StreamUnit::LexicalUnit(analyses) => {
+            analyses
+                .wordbound()
+                .map_or(0, |wordbound| 4 + len(wordbound))
+                + match &analyses.surface {
+                    Some(surface) => {
+                        2 + ling_form_len(surface, len)
+                            + analyses
+                                .iter()
+                                .map(|sub_lu| 1 + sub_lu_len(sub_lu, len))
+                                .sum::<usize>()
+                    }
+                    None => analyses_len(analyses, |sub_lu| sub_lu_len(sub_lu, len)),
+                }
+        }
+        StreamUnit::JoinedLexicalUnit(analyses) => analyses_len(analyses, |sub_lus| {
+            sub_lus.len().saturating_sub(1)
+                + sub_lus
+                    .iter()
+                    .map(|sub_lu| sub_lu_len(sub_lu, len))
+                    .sum::<usize>()
+        }),
+        StreamUnit::Chunk(head, children) => {
+            sub_lu_len(head, len)
+                + if head.delimited_chunk { 4 } else { 2 }
+                + children
+                    .iter()
+                    .map(|child| stream_unit_len(child, len))
+                    .sum::<usize>()
+        }
+        StreamUnit::Format(format) => len(format) + 2,
+        StreamUnit::Superblank(superblank) => len(superblank) + 4,
+        StreamUnit::NullFlush => 1,
+        StreamUnit::Space(s) | StreamUnit::Preamble(s) | StreamUnit::Unknown(s) => len(s),
+    }
+}
+
+fn char_count(s: &str) -> usize {
+    s.chars().count()
+}
+
+impl StreamUnit {
+    pub fn serialized_len(&self) -> usize {
+        stream_unit_len(self, str::len)
+    }
+
+    pub fn serialized_char_len(&self) -> usize {
+        stream_unit_len(self, char_count)
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(flag_symbol(self))
+    }
+}
+
+impl fmt::Display for SubLU {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::with_capacity(sub_lu_len(self, str::len));
+        write_sub_lu(&mut out, self, Dialect::DEFAULT);
+        f.write_str(&out)
+    }
+}
+
+impl fmt::Display for StreamUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::with_capacity(self.serialized_len());
+        write_stream_unit(&mut out, self);
+        f.write_str(&out)
+    }
+}
+
+pub fn serialize_stream(units: &[StreamUnit]) -> String {
+    let mut out = String::with_capacity(units.iter().map(StreamUnit::serialized_len).sum());
+    for unit in units {
+        write_stream_unit(&mut out, unit);
+    }
+    out
+}
+
+/// Writes the stream in the form `options` parses: chunks with its
+/// delimiters, escaped inside lemmas, and with or without the `$` after a
+/// `^`-delimited chunk; with quoted lemmas, a lemma holding a `#` that is
+/// no invariable mark is quoted.
+pub fn serialize_stream_with_options(units: &[StreamUnit], options: &ParserOptions) -> String {
+    let mut out = String::with_capacity(units.iter().map(StreamUnit::serialized_len).sum());
+    for unit in units {
+        write_stream_unit_with(&mut out, unit, Dialect::of(options));
+    }
+    out
+}
+
+impl Stream {
+    /// Ling forms are written with canonical minimal escaping: only
+    /// characters that are reserved inside a lexical unit get a backslash.
+    /// A source kept by `with_source` is returned as is while unchanged.
+    pub fn to_stream_string(&self) -> String {
+        match self.unchanged_source() {
+            Some(source) => String::from(source),
+            None => serialize_stream(&self.units),
+        }
+    }
+
+    /// Like `to_stream_string`, but tags are re-escaped canonically instead
+    /// of reproducing their raw spans.
+    pub fn to_canonical_stream_string(&self) -> String {
+        let mut canonical = self.clone();
+        for_each_sub_lu_mut(&mut canonical.units, &mut |sub_lu| {
+            sub_lu.raw_tag_spans = None
+        });
+        serialize_stream(&canonical.units)
+    }
+
+    /// The length of `to_stream_string`, taken from a kept source when that
+    /// is what it returns.
+    pub fn serialized_byte_len(&self) -> usize {
+        match self.unchanged_source() {
+            Some(source) => source.len(),
+            None => self.units.iter().map(StreamUnit::serialized_len).sum(),
+        }
+    }
+
+    pub fn serialized_char_len(&self) -> usize {
+        match self.unchanged_source() {
+            Some(source) => source.chars().count(),
+            None => self.units.iter().map(StreamUnit::serialized_char_len).sum(),
+        }
+    }
+}
+
+fn nth_or_last<T: Clone>(analyses: &[T], n: usize) -> Vec<T> {
+    analyses
+        .get(n)
+        .or_else(|| analyses.last())
+        .into_iter()
+        .cloned()
+        .collect()
+}
+
+/// Counts analyses as `LexicalUnit::analyses` does, so a surface form is
+/// never selected; it is dropped along with the other analyses, while a
+/// wordbound blank and a kept surface stay.
+pub fn select_nth_analysis(stream: &[StreamUnit], n: usize) -> Vec<StreamUnit> {
+    stream
+        .iter()
+        .map(|unit| match unit {
+            StreamUnit::LexicalUnit(lu) => StreamUnit::LexicalUnit(LexicalUnit {
+                readings: nth_or_last(lu.analyses(), n),
+                wordbound: lu.wordbound.clone(),
+                surface: lu.surface.clone(),
+            }),
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                StreamUnit::JoinedLexicalUnit(nth_or_last(joined_analyses(analyses), n))
+            }
+            StreamUnit::Chunk(head, children) => {
+                StreamUnit::Chunk(head.clone(), select_nth_analysis(children, n))
+            }
+            _ => unit.clone(),
+        })
+        .collect()
+}
+
+fn push_lexical_units<'a>(out: &mut Vec<&'a StreamUnit>, stream: &'a [StreamUnit]) {
+    for unit in stream {
+        match unit {
+            StreamUnit::Chunk(_, children) => push_lexical_units(out, children),
+            _ if unit.is_lexical_unit() => out.push(unit),
+            _ => {}
+        }
+    }
+}
+
+fn lexical_units(stream: &[StreamUnit]) -> Vec<&StreamUnit> {
+    let mut units = vec![];
+    push_lexical_units(&mut units, stream);
+    units
+}
+
+pub fn annotate_coverage(stream: &[StreamUnit], known: &HashSet<String>) -> Vec<bool> {
+    lexical_units(stream)
+        .into_iter()
+        .map(|unit| {
+            unit.first_analysis()
+                .is_some_and(|sub_lu| known.contains(&sub_lu.ling_form))
+        })
+        .collect()
+}
+
+pub fn coverage_ratio(stream: &[StreamUnit], known: &HashSet<String>) -> f64 {
+    let coverage = annotate_coverage(stream, known);
+    if coverage.is_empty() {
+        return 0.0;
+    }
+    coverage.iter().filter(|covered| **covered).count() as f64 / coverage.len() as f64
+}
+
+pub fn unknown_rate(stream: &[StreamUnit]) -> f64 {
+    let units = lexical_units(stream);
+    if units.is_empty() {
+        return 0.0;
+    }
+    units.iter().filter(|unit| unit.is_unknown()).count() as f64 / units.len() as f64
+}
+
+fn for_each_sub_lu<'a, F: FnMut(&'a SubLU)>(stream: &'a [StreamUnit], f: &mut F) {
+    for unit in stream {
+        match unit {
+            StreamUnit::LexicalUnit(analyses) => analyses.iter().for_each(&mut *f),
+            StreamUnit::JoinedLexicalUnit(analyses) => analyses.iter().flatten().for_each(&mut *f),
+            StreamUnit::Chunk(head, children) => {
+                f(head);
+                for_each_sub_lu(children, f);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn sub_lus(units: &[StreamUnit]) -> impl Iterator<Item = &SubLU> {
+    let mut all = vec![];
+    for_each_sub_lu(units, &mut |sub_lu| all.push(sub_lu));
+    all.into_iter()
+}
+
+fn for_each_sub_lu_mut<F: FnMut(&mut SubLU)>(stream: &mut [StreamUnit], f: &mut F) {
+    for unit in stream {
+        match unit {
+            StreamUnit::LexicalUnit(analyses) => analyses.iter_mut().for_each(&mut *f),
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                analyses.iter_mut().flatten().for_each(&mut *f)
+            }
+            StreamUnit::Chunk(head, children) => {
+                f(head);
+                for_each_sub_lu_mut(children, f);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// NFC-normalizes ling forms and invariable parts in place. Compound parts
+/// are normalized one by one, as the parser does, and their boundaries
+/// moved to the new part lengths.
+#[cfg(feature = "unicode-normalization")]
+pub fn normalize_stream_nfc(stream: &mut [StreamUnit]) {
+    for_each_sub_lu_mut(stream, &mut |sub_lu| {
+        let parts: Vec<String> = sub_lu
+            .compound_parts()
+            .into_iter()
+            .map(|part| part.nfc().collect())
+            .collect();
+        let mut boundaries = vec![];
+        for part in &parts[..parts.len() - 1] {
+            boundaries.push(boundaries.last().unwrap_or(&0) + part.len());
+        }
+        sub_lu.ling_form = parts.concat();
+        sub_lu.compound_boundaries = boundaries;
+        if let Some(invariable) = &mut sub_lu.invariable {
+            *invariable = invariable.nfc().collect();
+        }
+    });
+}
+
+pub fn truncate_stream_tags(stream: &mut [StreamUnit], n: usize) {
+    for_each_sub_lu_mut(stream, &mut |sub_lu| sub_lu.truncate_tags(n));
+}
+
+pub fn strip_tags(mut stream: Vec<StreamUnit>) -> Vec<StreamUnit> {
+    for_each_sub_lu_mut(&mut stream, &mut |sub_lu| {
+        sub_lu.tags.clear();
+        sub_lu.raw_tag_spans = None;
+    });
+    stream
+}
+
+/// Pairs are unordered, so each key holds the two tags in sorted order.
+pub fn tag_cooccurrence(stream: &[StreamUnit]) -> HashMap<(String, String), usize> {
+    let mut counts = HashMap::new();
+    for_each_sub_lu(stream, &mut |sub_lu| {
+        for (i, a) in sub_lu.tags.iter().enumerate() {
+            for b in &sub_lu.tags[i + 1..] {
+                let pair = if a <= b { (a, b) } else { (b, a) };
+                *counts.entry((pair.0.clone(), pair.1.clone())).or_insert(0) += 1;
+            }
+        }
+    });
+    counts
+}
+
+fn anonymize_sub_lu(sub_lu: &mut SubLU, placeholder: &str) {
+    sub_lu.ling_form = String::from(placeholder);
+    sub_lu.compound_boundaries.clear();
+    if sub_lu.invariable.is_some() {
+        sub_lu.invariable = Some(String::from(placeholder));
+    }
+}
+
+/// Chunk heads are anonymized too, leaving their tags, and so is a surface
+/// kept by `ParserOptions::keep_surface`.
+pub fn anonymize_lemmas(mut stream: Vec<StreamUnit>, placeholder: &str) -> Vec<StreamUnit> {
+    apply_to_units(&mut stream, &mut |unit| {
+        if let StreamUnit::LexicalUnit(lu) = unit {
+            if lu.surface.is_some() {
+                lu.surface = Some(String::from(placeholder));
+            }
+        }
+    });
+    for_each_sub_lu_mut(&mut stream, &mut |sub_lu| {
+        anonymize_sub_lu(sub_lu, placeholder)
+    });
+    stream
+}
+
+fn push_observations(
+    out: &mut Vec<(String, Vec<String>)>,
+    stream: &[StreamUnit],
+    coarse: &dyn Fn(&SubLU) -> String,
+) {
+    for unit in stream {
+        match unit {
+            StreamUnit::LexicalUnit(lu) if !lu.is_empty() => {
+                let observation = lu.kept_surface().unwrap_or(&lu[0].ling_form);
+                out.push((
+                    String::from(observation),
+                    lu.analyses().iter().map(coarse).collect(),
+                ));
+            }
+            StreamUnit::JoinedLexicalUnit(analyses) if !analyses.is_empty() => {
+                let observation = analyses[0]
+                    .iter()
+                    .map(|sub_lu| sub_lu.ling_form.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("+");
+                let states = joined_analyses(analyses)
+                    .iter()
+                    .map(|sub_lus| {
+                        sub_lus
+                            .iter()
+                            .map(coarse)
+                            .collect::<Vec<String>>()
+                            .join("+")
+                    })
+                    .collect();
+                out.push((observation, states));
+            }
+            StreamUnit::Chunk(_, children) => push_observations(out, children, coarse),
+            _ => {}
+        }
+    }
+}
+
+/// A surface form, as told apart by `LexicalUnit::analyses`, is the
+/// observation and is not turned into a state.
+pub fn to_observations(
+    stream: &[StreamUnit],
+    coarse: &dyn Fn(&SubLU) -> String,
+) -> Vec<(String, Vec<String>)> {
+    let mut observations = vec![];
+    push_observations(&mut observations, stream, coarse);
+    observations
+}
+
+pub fn into_lemma_only(stream: Vec<StreamUnit>) -> Vec<StreamUnit> {
+    stream
+        .into_iter()
+        .map(StreamUnit::into_lemma_only)
+        .collect()
+}
+
+pub fn chunks_to_heads(stream: Vec<StreamUnit>) -> Vec<StreamUnit> {
+    stream
+        .into_iter()
+        .map(StreamUnit::chunk_to_head_lu)
+        .collect()
+}
+
+pub fn flat_map_units<F: FnMut(StreamUnit) -> Vec<StreamUnit>>(
+    stream: Vec<StreamUnit>,
+    f: F,
+) -> Vec<StreamUnit> {
+    stream.into_iter().flat_map(f).collect()
+}
+
+pub fn tag_count(stream: &[StreamUnit]) -> usize {
+    stream.iter().map(StreamUnit::tag_count).sum()
+}
+
+pub fn mixed_flag_units(stream: &[StreamUnit]) -> Vec<usize> {
+    stream
+        .iter()
+        .enumerate()
+        .filter(|(_, unit)| unit.has_mixed_flags())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn push_longest<'a>(longest: &mut Option<(&'a StreamUnit, usize)>, stream: &'a [StreamUnit]) {
+    for unit in stream {
+        let lemma = match unit {
+            StreamUnit::Chunk(head, children) => {
+                push_longest(longest, children);
+                Some(head)
+            }
+            _ => unit.first_analysis(),
+        };
+        if let Some(sub_lu) = lemma {
+            let len = sub_lu.ling_form.chars().count();
+            match *longest {
+                Some((_, max)) if max >= len => {}
+                _ => *longest = Some((unit, len)),
+            }
+        }
+    }
+}
+
+/// Lengths count Unicode scalar values of the first analysis' lemma, or of
+/// the head for a chunk, whose children are searched as well.
+pub fn longest_lexical_unit(stream: &[StreamUnit]) -> Option<(&StreamUnit, usize)> {
+    let mut longest = None;
+    push_longest(&mut longest, stream);
+    longest
+}
+
+pub fn max_chunk_depth(stream: &[StreamUnit]) -> usize {
+    stream.iter().map(StreamUnit::depth).max().unwrap_or(0)
+}
+
+pub fn text_to_stream(text: &str) -> Vec<StreamUnit> {
+    let mut stream = vec![];
+    let mut rest = text;
+    while let Some(first) = rest.chars().next() {
+        let is_space = first.is_whitespace();
+        let end = rest
+            .find(|c: char| c.is_whitespace() != is_space)
+            .unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(end);
+        stream.push(if is_space {
+            StreamUnit::Space(String::from(run))
+        } else {
+            StreamUnit::lexical_unit(vec![SubLU {
+                ling_form: String::from(run),
+                flags: vec![Flag::Unanalyzed],
+                ..Default::default()
+            }])
+        });
+        rest = tail;
+    }
+    stream
+}
+
+fn blank_text(unit: &StreamUnit) -> Option<&str> {
+    match unit {
+        StreamUnit::Space(blank) | StreamUnit::Format(blank) | StreamUnit::Superblank(blank) => {
+            Some(blank)
+        }
+        _ => None,
+    }
+}
+
+pub fn units_with_blanks(stream: &[StreamUnit]) -> Vec<(Option<&str>, &StreamUnit, Option<&str>)> {
+    stream
+        .iter()
+        .enumerate()
+        .filter(|(_, unit)| blank_text(unit).is_none())
+        .map(|(i, unit)| {
+            let before = i.checked_sub(1).and_then(|j| blank_text(&stream[j]));
+            let after = stream.get(i + 1).and_then(blank_text);
+            (before, unit, after)
+        })
+        .collect()
+}
+
+/// A lexical unit with no analyses or only empty ling forms, or a chunk
+/// holding one.
+fn has_empty_unit(unit: &StreamUnit) -> bool {
+    let is_empty = |sub_lu: &SubLU| sub_lu.ling_form.is_empty();
+    match unit {
+        StreamUnit::LexicalUnit(analyses) => analyses.iter().all(is_empty),
+        StreamUnit::JoinedLexicalUnit(analyses) => analyses.iter().flatten().all(is_empty),
+        StreamUnit::Chunk(_, children) => children.iter().any(has_empty_unit),
+        _ => false,
+    }
+}
+
+pub fn all_units_analyzed(stream: &[StreamUnit]) -> bool {
+    !stream.iter().any(has_empty_unit)
+}
+
+/// Indices of the top-level units that `all_units_analyzed` rejects.
+pub fn empty_unit_indices(stream: &[StreamUnit]) -> Vec<usize> {
+    stream
+        .iter()
+        .enumerate()
+        .filter(|(_, unit)| has_empty_unit(unit))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+pub fn strip_formats(stream: Vec<StreamUnit>) -> Vec<StreamUnit> {
+    stream
+        .into_iter()
+        .filter(|unit| !matches!(unit, StreamUnit::Format(_)))
+        .map(|unit| unit.with_chunk_children(strip_formats))
+        .collect()
+}
+
+/// Drops a lexical unit equal to the one right before it. With
+/// `ignore_blanks` the previous unit may sit behind spaces, which are then
+/// dropped along with the duplicate; formats and superblanks are markup and
+/// keep both units.
+pub fn dedup_adjacent_lus(stream: Vec<StreamUnit>, ignore_blanks: bool) -> Vec<StreamUnit> {
+    let mut deduped: Vec<StreamUnit> = vec![];
+    for unit in stream {
+        if matches!(
+            unit,
+            StreamUnit::LexicalUnit(_) | StreamUnit::JoinedLexicalUnit(_)
+        ) {
+            let previous = deduped
+                .iter()
+                .rposition(|prev| !ignore_blanks || !matches!(prev, StreamUnit::Space(_)));
+            if let Some(previous) = previous {
+                if deduped[previous] == unit {
+                    deduped.truncate(previous + 1);
+                    continue;
+                }
+            }
+        }
+        deduped.push(unit);
+    }
+    deduped
+}
+
+pub fn single_space(stream: Vec<StreamUnit>) -> Vec<StreamUnit> {
+    let mut spaced: Vec<StreamUnit> = vec![];
+    for unit in stream {
+        match unit {
+            StreamUnit::Space(_) => {
+                if !matches!(spaced.last(), None | Some(StreamUnit::Space(_))) {
+                    spaced.push(StreamUnit::Space(String::from(" ")));
+                }
+            }
+            _ => spaced.push(unit),
+        }
+    }
+    if matches!(spaced.last(), Some(StreamUnit::Space(_))) {
+        spaced.pop();
+    }
+    spaced
+}
+
+pub fn split_sentences(stream: Vec<StreamUnit>, options: &ParserOptions) -> Vec<Vec<StreamUnit>> {
+    let mut sentences = vec![];
+    let mut sentence = vec![];
+    for unit in stream {
+        let is_boundary = unit.is_sentence_boundary_with(options);
+        sentence.push(unit);
+        if is_boundary {
+            sentences.push(std::mem::take(&mut sentence));
+        }
+    }
+    if !sentence.is_empty() {
+        sentences.push(sentence);
+    }
+    sentences
+}
+
+fn push_chunk_labels(out: &mut Vec<String>, stream: &[StreamUnit]) {
+    for unit in stream {
+        if let StreamUnit::Chunk(head, children) = unit {
+            out.push(head.ling_form.clone());
+            push_chunk_labels(out, children);
+        }
+    }
+}
+
+pub fn chunk_labels(stream: &[StreamUnit]) -> Vec<String> {
+    let mut labels = vec![];
+    push_chunk_labels(&mut labels, stream);
+    labels
+}
+
+#[cfg(feature = "test-utils")]
+pub mod test_utils {
+    use super::*;
+
+    fn sub_lu_difference(a: &SubLU, b: &SubLU) -> Option<String> {
+        if a.flags != b.flags {
+            Some(format!("flags: {:?} != {:?}", a.flags, b.flags))
+        } else if a.ling_form != b.ling_form {
+            Some(format!("ling_form: {:?} != {:?}", a.ling_form, b.ling_form))
+        } else if a.invariable != b.invariable {
+            Some(format!(
+                "invariable: {:?} != {:?}",
+                a.invariable, b.invariable
+            ))
+        } else if a.tags != b.tags {
+            Some(format!("tags: {:?} != {:?}", a.tags, b.tags))
+        } else if a.weight != b.weight {
+            Some(format!("weight: {:?} != {:?}", a.weight, b.weight))
+        } else if a.joins_next != b.joins_next {
+            Some(format!(
+                "joins_next: {:?} != {:?}",
+                a.joins_next, b.joins_next
+            ))
+        } else if a.delimited_chunk != b.delimited_chunk {
+            Some(format!(
+                "delimited_chunk: {:?} != {:?}",
+                a.delimited_chunk, b.delimited_chunk
+            ))
+        } else if a.gen_failed != b.gen_failed {
+            Some(format!(
+                "gen_failed: {:?} != {:?}",
+                a.gen_failed, b.gen_failed
+            ))
+        } else if a.compound_boundaries != b.compound_boundaries {
+            Some(format!(
+                "compound_boundaries: {:?} != {:?}",
+                a.compound_boundaries, b.compound_boundaries
+            ))
+        } else if a.raw_tag_spans != b.raw_tag_spans {
+            Some(format!(
+                "raw_tag_spans: {:?} != {:?}",
+                a.raw_tag_spans, b.raw_tag_spans
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn sub_lus_difference(a: &[SubLU], b: &[SubLU]) -> Option<String> {
+        a.iter()
+            .zip(b)
+            .enumerate()
+            .find_map(|(i, (a, b))| sub_lu_difference(a, b).map(|d| format!("[{}].{}", i, d)))
+            .or_else(|| {
+                if a.len() != b.len() {
+                    Some(format!("length: {} != {}", a.len(), b.len()))
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn unit_difference(a: &StreamUnit, b: &StreamUnit) -> String {
+        match (a, b) {
+            (StreamUnit::LexicalUnit(a), StreamUnit::LexicalUnit(b)) => {
+                if a.wordbound != b.wordbound {
+                    format!("wordbound: {:?} != {:?}", a.wordbound, b.wordbound)
+                } else if a.surface != b.surface {
+                    format!("kept surface: {:?} != {:?}", a.surface, b.surface)
+                } else {
+                    sub_lus_difference(a, b).map_or(String::new(), |d| format!("analysis {}", d))
+                }
+            }
+            (StreamUnit::JoinedLexicalUnit(a), StreamUnit::JoinedLexicalUnit(b)) => a
+                .iter()
+                .zip(b)
+                .enumerate()
+                .find_map(|(i, (a, b))| {
+                    sub_lus_difference(a, b).map(|d| format!("analysis [{}] sub-unit {}", i, d))
+                })
+                .unwrap_or_else(|| format!("analysis count: {} != {}", a.len(), b.len())),
+            (StreamUnit::Chunk(a_head, a_children), StreamUnit::Chunk(b_head, b_children)) => {
+                match sub_lu_difference(a_head, b_head) {
+                    Some(d) => format!("chunk head {}", d),
+                    None => match first_difference(a_children, b_children) {
+                        Some(d) => format!("chunk child {}", d),
+                        None => String::new(),
+                    },
+                }
+            }
+            _ => format!("unit: {:?} != {:?}", a, b),
+        }
+    }
+
+    fn first_difference(a: &[StreamUnit], b: &[StreamUnit]) -> Option<String> {
+        a.iter()
+            .zip(b)
+            .position(|(a, b)| a != b)
+            .map(|i| format!("{}: {}", i, unit_difference(&a[i], &b[i])))
+            .or_else(|| {
+                if a.len() != b.len() {
+                    Some(format!(
+                        "{}: stream length {} != {}",
+                        a.len().min(b.len()),
+                        a.len(),
+                        b.len()
+                    ))
+                } else {
+                    None
+                }
+            })
+    }
+
+    pub fn assert_streams_eq(a: &[StreamUnit], b: &[StreamUnit]) {
+        if let Some(difference) = first_difference(a, b) {
+            panic!(
+                "streams differ at unit {}\n  left: {:?}\n right: {:?}",
+                difference, a, b
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
     fn basic_lu() {
         assert_eq!(
-            parse_stream_unit("^กา$"),
-            Ok((
-                "",
-                StreamUnit::LexicalUnit(vec![SubLU {
-                    ling_form: String::from("กา"),
-                    tags: vec![],
-                    flag: Flag::Nothing,
-                }])
-            ))
+            parse_stream_unit("^กา$"),
+            Ok((
+                "",
+                StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("กา"),
+                    tags: vec![],
+                    ..Default::default()
+                }])
+            ))
+        );
+    }
+
+    #[test]
+    fn lu_surface_escape() {
+        assert_eq!(
+            parse_stream_unit("^\\^ab\\$$"),
+            Ok((
+                "",
+                StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("^ab$"),
+                    tags: vec![],
+                    ..Default::default()
+                }])
+            ))
+        );
+    }
+
+    #[test]
+    fn ambiguous_lu() {
+        assert_eq!(
+            parse_stream_unit("^ab/xy$"),
+            Ok((
+                "",
+                StreamUnit::lexical_unit(vec![
+                    SubLU {
+                        ling_form: String::from("ab"),
+                        tags: vec![],
+                        ..Default::default()
+                    },
+                    SubLU {
+                        ling_form: String::from("xy"),
+                        tags: vec![],
+                        ..Default::default()
+                    }
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn basic_lus() {
+        assert_eq!(
+            parse_stream("^ab$"),
+            Ok((
+                "",
+                vec![StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("ab"),
+                    tags: vec![],
+                    ..Default::default()
+                }])]
+            ))
+        );
+        assert_eq!(
+            parse_stream("^ab$ ^cd$"),
+            Ok((
+                "",
+                vec![
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("ab"),
+                        tags: vec![],
+                        ..Default::default()
+                    }]),
+                    StreamUnit::Space(String::from(" ")),
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("cd"),
+                        tags: vec![],
+                        ..Default::default()
+                    }])
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_basic_tag() {
+        assert_eq!(parse_tag("<n>"), Ok(("", "n")));
+    }
+
+    #[test]
+    fn parse_basic_stream_with_tags() {
+        assert_eq!(
+            parse_stream("^ab/xy<n>$ ^cd$"),
+            Ok((
+                "",
+                vec![
+                    StreamUnit::lexical_unit(vec![
+                        SubLU {
+                            ling_form: String::from("ab"),
+                            tags: vec![],
+                            ..Default::default()
+                        },
+                        SubLU {
+                            ling_form: String::from("xy"),
+                            tags: vec![String::from("n")],
+                            ..Default::default()
+                        }
+                    ]),
+                    StreamUnit::Space(String::from(" ")),
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("cd"),
+                        tags: vec![],
+                        ..Default::default()
+                    }])
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_basic_stream_with_tags_sans_space() {
+        assert_eq!(
+            parse_stream("^ab/xy<n>$^cd$"),
+            Ok((
+                "",
+                vec![
+                    StreamUnit::lexical_unit(vec![
+                        SubLU {
+                            ling_form: String::from("ab"),
+                            tags: vec![],
+                            ..Default::default()
+                        },
+                        SubLU {
+                            ling_form: String::from("xy"),
+                            tags: vec![String::from("n")],
+                            ..Default::default()
+                        }
+                    ]),
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("cd"),
+                        tags: vec![],
+                        ..Default::default()
+                    }])
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_basic_stream_with_tags_sans_space_with_format() {
+        assert_eq!(
+            parse_stream("[<j>]^ab/xy<n>$[</j>]^cd$"),
+            Ok((
+                "",
+                vec![
+                    StreamUnit::Format(String::from("<j>")),
+                    StreamUnit::lexical_unit(vec![
+                        SubLU {
+                            ling_form: String::from("ab"),
+                            tags: vec![],
+                            ..Default::default()
+                        },
+                        SubLU {
+                            ling_form: String::from("xy"),
+                            tags: vec![String::from("n")],
+                            ..Default::default()
+                        }
+                    ]),
+                    StreamUnit::Format(String::from("</j>")),
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("cd"),
+                        tags: vec![],
+                        ..Default::default()
+                    }])
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_joined_lu_basic() {
+        assert_eq!(
+            parse_stream_unit("^ab/xy<n>+tx<a>$"),
+            Ok((
+                "",
+                StreamUnit::JoinedLexicalUnit(vec![
+                    vec![SubLU {
+                        ling_form: String::from("ab"),
+                        tags: vec![],
+                        ..Default::default()
+                    }],
+                    vec![
+                        SubLU {
+                            ling_form: String::from("xy"),
+                            tags: vec![String::from("n")],
+                            ..Default::default()
+                        },
+                        SubLU {
+                            ling_form: String::from("tx"),
+                            tags: vec![String::from("a")],
+                            ..Default::default()
+                        }
+                    ],
+                ]),
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_chunk_with_format() {
+        assert_eq!(
+            parse_stream_unit("N1<SN><a>{^i$ [<o>]^j$[</o>]^k$}"),
+            Ok((
+                "",
+                StreamUnit::Chunk(
+                    SubLU {
+                        ling_form: String::from("N1"),
+                        tags: vec![String::from("SN"), String::from("a")],
+                        ..Default::default()
+                    },
+                    vec![
+                        StreamUnit::lexical_unit(vec![SubLU {
+                            ling_form: String::from("i"),
+                            tags: vec![],
+                            ..Default::default()
+                        }]),
+                        StreamUnit::Space(String::from(" ")),
+                        StreamUnit::Format(String::from("<o>")),
+                        StreamUnit::lexical_unit(vec![SubLU {
+                            ling_form: String::from("j"),
+                            tags: vec![],
+                            ..Default::default()
+                        }]),
+                        StreamUnit::Format(String::from("</o>")),
+                        StreamUnit::lexical_unit(vec![SubLU {
+                            ling_form: String::from("k"),
+                            tags: vec![],
+                            ..Default::default()
+                        }]),
+                    ],
+                ),
+            ))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn chunk_with_format_json_round_trip() {
+        let (_, chunk) = parse_stream_unit("N1<SN><a>{^i$ [<o>]^j$[</o>]^k$}").unwrap();
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert!(json.starts_with(r#"{"type":"Chunk","value":[{"ling_form":"N1""#));
+        assert!(json.contains(r#"{"type":"Format","value":"<o>"}"#));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["value"][1][0]["type"], "LexicalUnit");
+        assert_eq!(serde_json::from_str::<StreamUnit>(&json).unwrap(), chunk);
+    }
+
+    #[test]
+    fn parse_escape_bracket() {
+        let raw = "^\\]<vblex><pres>$";
+        let (i, _) = parse_stream_unit(raw).unwrap();
+        assert_eq!(i.len(), 0);
+    }
+
+    #[test]
+    fn parse_at_lu() {
+        let raw = "^\\@<det><ind><sg>$";
+        let (i, _) = parse_stream_unit(raw).unwrap();
+        assert_eq!(i.len(), 0);
+    }
+
+    #[test]
+    fn parse_special_lemma() {
+        let raw = "^*t<det><ind><sg>$";
+        let (i, su) = parse_stream_unit(raw).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            su,
+            StreamUnit::lexical_unit(vec![SubLU {
+                ling_form: String::from("t"),
+                tags: vec![String::from("det"), String::from("ind"), String::from("sg")],
+                flags: vec![Flag::Unanalyzed],
+                ..Default::default()
+            }])
+        )
+    }
+
+    #[test]
+    fn parse_special_lemma_only() {
+        let raw = "^*<det><ind><sg>$";
+        let (i, su) = parse_stream_unit(raw).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            su,
+            StreamUnit::lexical_unit(vec![SubLU {
+                ling_form: String::from(""),
+                tags: vec![String::from("det"), String::from("ind"), String::from("sg")],
+                flags: vec![Flag::Unanalyzed],
+                ..Default::default()
+            }])
+        )
+    }
+
+    #[test]
+    fn parse_large_thai_data() {
+        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+        let (i, stream) = parse_stream(&raw).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            stream
+                .into_iter()
+                .filter_map(|su| match su {
+                    StreamUnit::Space(_) => None,
+                    StreamUnit::LexicalUnit(analyses) => Some(analyses[0].ling_form.clone()),
+                    _ => Some(String::from("_")),
+                })
+                .count(),
+            5
+        );
+    }
+
+    #[test]
+    fn log_line_of_large_thai_data() {
+        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+        let (_, stream) = parse_stream(&raw).unwrap();
+        assert_eq!(
+            to_log_line(&stream),
+            "prpers.prn like.vblex a.det dog.n..sent"
+        );
+    }
+
+    #[test]
+    fn log_line_skips_formats() {
+        let (_, stream) = parse_stream("[<j>]^ab/xy<n>$[</j>]  ^cd$").unwrap();
+        assert_eq!(to_log_line(&stream), "xy.n cd");
+        let (_, stream) = parse_stream(" ^dogs/dog<n><pl>$ \n\t^bark<vblex>$^.<sent>$\n").unwrap();
+        assert_eq!(to_log_line(&stream), "dog.n bark.vblex..sent");
+    }
+
+    #[test]
+    fn parse_join_directive() {
+        let (i, su) = parse_stream_unit("^lem<n><j/>$").unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            su,
+            StreamUnit::lexical_unit(vec![SubLU {
+                ling_form: String::from("lem"),
+                tags: vec![String::from("n")],
+                joins_next: true,
+                ..Default::default()
+            }])
+        );
+        match su {
+            StreamUnit::LexicalUnit(analyses) => assert!(analyses[0].joins_next()),
+            _ => panic!("expected a lexical unit"),
+        }
+        let (_, stream) = parse_stream("^a<n><j/>$ ^b<n>$ ^c$").unwrap();
+        assert_eq!(surface_text(&stream), "ab c");
+        assert_eq!(
+            Stream::from(stream).to_stream_string(),
+            "^a<n><j/>$ ^b<n>$ ^c$"
+        );
+    }
+
+    #[test]
+    fn parse_preamble_before_stream() {
+        let options = ParserOptions {
+            preamble: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_stream_with_options("# mode: foo\n^word$", &options),
+            Ok((
+                "",
+                vec![
+                    StreamUnit::Preamble(String::from("# mode: foo\n")),
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("word"),
+                        ..Default::default()
+                    }])
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn preamble_is_off_by_default() {
+        let (i, stream) =
+            parse_stream_with_options("# mode: foo\n^word$", &ParserOptions::default()).unwrap();
+        assert_eq!(i, "# mode: foo\n^word$");
+        assert_eq!(stream, vec![]);
+    }
+
+    #[test]
+    fn serialized_len_matches_stream_string() {
+        let raw = "^\\^กา\\$<n>$ [<j>]^c\\/d/e<n>+f<v>$\tN1<SN>{^i\\{$ ^j<n><j/>$}\n";
+        let (i, units) = parse_stream(raw).unwrap();
+        assert_eq!(i.len(), 0);
+        let stream = Stream::from(units);
+        let serialized = stream.to_stream_string();
+        assert_eq!(serialized, raw);
+        assert_eq!(stream.serialized_byte_len(), serialized.len());
+        assert_eq!(stream.serialized_char_len(), serialized.chars().count());
+    }
+
+    #[test]
+    fn parse_invariable_part_across_join() {
+        assert_eq!(
+            parse_stream_unit("^go#away+PAST$"),
+            Ok((
+                "",
+                StreamUnit::JoinedLexicalUnit(vec![vec![
+                    SubLU {
+                        ling_form: String::from("go"),
+                        invariable: Some(String::from("away")),
+                        ..Default::default()
+                    },
+                    SubLU {
+                        ling_form: String::from("PAST"),
+                        ..Default::default()
+                    }
+                ]])
+            ))
+        );
+    }
+
+    #[test]
+    fn serialize_invariable_part() {
+        let raw = "^go#away<vblex>+PAST$";
+        let (_, units) = parse_stream(raw).unwrap();
+        assert_eq!(Stream::from(units).to_stream_string(), raw);
+    }
+
+    #[test]
+    fn parse_recovering_skips_malformed_lu() {
+        let (stream, errors) = parse_recovering("^ab$ ^c<d$ ^ef$");
+        assert_eq!(
+            stream,
+            vec![
+                StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("ab"),
+                    ..Default::default()
+                }]),
+                StreamUnit::Space(String::from(" ")),
+                StreamUnit::Space(String::from(" ")),
+                StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("ef"),
+                    ..Default::default()
+                }]),
+            ]
+        );
+        assert_eq!(errors, vec![ParseError::Syntax { offset: 5 }]);
+        let (stream, errors) = parse_recovering("^a/a<n> a<adj>$");
+        assert!(errors.is_empty());
+        assert_eq!(stream.len(), 1);
+        assert_eq!(stream[0].ling_forms(), vec!["a", "a", "a"]);
+    }
+
+    #[test]
+    fn parse_recovering_reports_why_units_failed() {
+        let options = ParserOptions {
+            tag_validator: Some(Box::new(|tag| tag == "n")),
+            ..Default::default()
+        };
+        let (stream, errors) = parse_recovering_with_options("^a<x>$ ^b<>$ ^c<n>$ ^d<n", &options);
+        assert_eq!(stream.len(), 4);
+        assert_eq!(stream[2].ling_forms(), vec!["c"]);
+        assert_eq!(
+            errors,
+            vec![
+                ParseError::UnknownTag {
+                    tag: String::from("x"),
+                    offset: 2
+                },
+                ParseError::EmptyTag { offset: 9 },
+                ParseError::Syntax { offset: 20 },
+            ]
+        );
+    }
+
+    #[test]
+    fn select_second_analysis() {
+        let (_, stream) = parse_stream("^a/b$ ^c$ N1<SN>{^d/e$}").unwrap();
+        let selected = Stream::from(select_nth_analysis(&stream, 1));
+        assert_eq!(selected.to_stream_string(), "^b$ ^c$ N1<SN>{^e$}");
+        let (_, stream) = parse_stream("^surf/lem<n>/lem<v>$ ^a+b/c<n>+d<v>$").unwrap();
+        let first = Stream::from(select_nth_analysis(&stream, 0));
+        assert_eq!(first.to_stream_string(), "^lem<n>$ ^c<n>+d<v>$");
+        let second = Stream::from(select_nth_analysis(&stream, 1));
+        assert_eq!(second.to_stream_string(), "^lem<v>$ ^c<n>+d<v>$");
+    }
+
+    #[test]
+    fn parse_flag_lemma_tags_combinations() {
+        let sub_lu = |flag: Flag, ling_form: &str, tags: &[&str]| {
+            let mut sub_lu = SubLU {
+                ling_form: String::from(ling_form),
+                tags: tags.iter().map(|tag| String::from(*tag)).collect(),
+                ..Default::default()
+            };
+            sub_lu.set_flag(flag);
+            sub_lu
+        };
+        let cases = vec![
+            ("^*lemma<n>$", sub_lu(Flag::Unanalyzed, "lemma", &["n"])),
+            ("^*lemma$", sub_lu(Flag::Unanalyzed, "lemma", &[])),
+            ("^*<n>$", sub_lu(Flag::Unanalyzed, "", &["n"])),
+            ("^*$", sub_lu(Flag::Unanalyzed, "", &[])),
+            ("^lemma<n>$", sub_lu(Flag::Nothing, "lemma", &["n"])),
+            ("^lemma$", sub_lu(Flag::Nothing, "lemma", &[])),
+            ("^<n>$", sub_lu(Flag::Nothing, "", &["n"])),
+            ("^$", sub_lu(Flag::Nothing, "", &[])),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(
+                parse_stream_unit(raw),
+                Ok(("", StreamUnit::lexical_unit(vec![expected]))),
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    fn assert_streams_eq_accepts_equal_streams() {
+        let (_, a) = parse_stream("^ab/xy<n>$ ^cd$").unwrap();
+        let (_, b) = parse_stream("^ab/xy<n>$ ^cd$").unwrap();
+        test_utils::assert_streams_eq(&a, &b);
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    #[should_panic(expected = "streams differ at unit 0: analysis [1].tags: [\"n\"] != [\"adj\"]")]
+    fn assert_streams_eq_reports_first_difference() {
+        let (_, a) = parse_stream("^ab/xy<n>$ ^cd$").unwrap();
+        let (_, b) = parse_stream("^ab/xy<adj>$ ^ce$").unwrap();
+        test_utils::assert_streams_eq(&a, &b);
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    #[should_panic(expected = "streams differ at unit 0: wordbound: Some(\"t:b:1\") != None")]
+    fn assert_streams_eq_reports_wordbound_difference() {
+        let (_, a) = parse_stream("[[t:b:1]]^a<n>$").unwrap();
+        let (_, b) = parse_stream("^a<n>$").unwrap();
+        test_utils::assert_streams_eq(&a, &b);
+    }
+
+    #[test]
+    fn parse_escaped_space_in_lemma() {
+        assert_eq!(
+            parse_stream_unit("^New\\ York<np>$"),
+            Ok((
+                "",
+                StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("New York"),
+                    tags: vec![String::from("np")],
+                    ..Default::default()
+                }])
+            ))
+        );
+    }
+
+    #[test]
+    fn surface_text_per_null_flush_segment() {
+        let file = std::fs::File::open("test_data/multi_segment.apertium_stream").unwrap();
+        let texts: Vec<Result<String, ParseError>> =
+            surface_text_from_reader(std::io::BufReader::new(file)).collect();
+        assert_eq!(
+            texts,
+            vec![
+                Ok(String::from("I like")),
+                Ok(String::from("a dog.")),
+                Ok(String::from("เป็น\n")),
+            ]
+        );
+    }
+
+    #[test]
+    fn surface_text_from_reader_reports_bad_segment() {
+        let input: &[u8] = b"^a$\0^b<$";
+        let texts: Vec<Result<String, ParseError>> = surface_text_from_reader(input).collect();
+        assert_eq!(
+            texts,
+            vec![Ok(String::from("a")), Err(ParseError::Syntax { offset: 0 })]
+        );
+    }
+
+    #[test]
+    fn unknown_units() {
+        let (_, stream) =
+            parse_stream("^dogz/*dogz$ ^cat/cat<n>$ ^*xyz$ N1<SN>{^the<det>$}").unwrap();
+        assert!(stream[0].is_unknown());
+        assert!(!stream[2].is_unknown());
+        assert!(stream[4].is_unknown());
+        assert!(!stream[1].is_unknown());
+        assert_eq!(unknown_rate(&stream), 0.5);
+        assert_eq!(unknown_rate(&[]), 0.0);
+    }
+
+    #[test]
+    fn chunk_labels_in_document_order() {
+        let (_, stream) = parse_stream("SN<SN>{^i<prn>$} SV<SV>{^like<vblex>$}").unwrap();
+        assert_eq!(chunk_labels(&stream), vec!["SN", "SV"]);
+        let nested = vec![StreamUnit::Chunk(
+            SubLU {
+                ling_form: String::from("S"),
+                ..Default::default()
+            },
+            stream,
+        )];
+        assert_eq!(chunk_labels(&nested), vec!["S", "SN", "SV"]);
+    }
+
+    #[test]
+    fn truncate_tags_to_two() {
+        let (_, mut su) = parse_stream_unit("^cat<n><sg><nom><def><x>$").unwrap();
+        if let StreamUnit::LexicalUnit(analyses) = &mut su {
+            analyses[0].truncate_tags(2);
+        }
+        assert_eq!(Stream::from(vec![su]).to_stream_string(), "^cat<n><sg>$");
+
+        let (_, mut stream) =
+            parse_stream("^a<n><sg><x>+b<v><pres>$ N1<SN><a><b>{^c<n><pl><y>$}").unwrap();
+        truncate_stream_tags(&mut stream, 2);
+        assert_eq!(
+            Stream::from(stream).to_stream_string(),
+            "^a<n><sg>+b<v><pres>$ N1<SN><a>{^c<n><pl>$}"
+        );
+    }
+
+    #[test]
+    fn stray_dollar_after_format() {
+        assert_eq!(
+            parse("[fmt]$^word$", &ParserOptions::default()),
+            Err(ParseError::UnexpectedDollar { offset: 5 })
+        );
+        let options = ParserOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("[fmt]$^word$", &options),
+            Ok(Stream::from(vec![
+                StreamUnit::Format(String::from("fmt")),
+                StreamUnit::Unknown(String::from("$")),
+                StreamUnit::lexical_unit(vec![SubLU {
+                    ling_form: String::from("word"),
+                    ..Default::default()
+                }]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn observations_with_first_tag() {
+        let (_, stream) = parse_stream("^a<n>/a<adj>$ ^dogs/dog<n><pl>$").unwrap();
+        let first_tag = |sub_lu: &SubLU| sub_lu.tags.first().cloned().unwrap_or_default();
+        assert_eq!(
+            to_observations(&stream, &first_tag),
+            vec![
+                (
+                    String::from("a"),
+                    vec![String::from("n"), String::from("adj")]
+                ),
+                (String::from("dogs"), vec![String::from("n")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn sniff_stream_input() {
+        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+        assert!(looks_like_stream(&raw));
+        assert!(looks_like_stream("[<p>]\n^ab/xy<n>$"));
+        assert!(!looks_like_stream("i like dogs, 2^3 costs $5"));
+        assert!(!looks_like_stream("<html><body>^x$</body></html>"));
+        assert!(!looks_like_stream(""));
+    }
+
+    #[test]
+    fn raw_tags_keep_brackets() {
+        let (_, sub_lu) = parse_sub_lu("cat<n><sg>").unwrap();
+        assert_eq!(
+            sub_lu.raw_tags().collect::<Vec<String>>(),
+            vec!["<n>", "<sg>"]
+        );
+        let (_, sub_lu) = parse_sub_lu(r"a<x\>y>").unwrap();
+        assert_eq!(sub_lu.tags(), &[String::from("x>y")]);
+        assert_eq!(sub_lu.raw_tags().collect::<Vec<String>>(), vec![r"<x\>y>"]);
+    }
+
+    #[test]
+    fn flat_map_splits_glued_punctuation() {
+        let (_, stream) = parse_stream("^word.<n>$ ^b$").unwrap();
+        let split = flat_map_units(stream, |unit| match unit {
+            StreamUnit::LexicalUnit(mut analyses) if analyses[0].ling_form.ends_with('.') => {
+                analyses[0].ling_form.pop();
+                let period = SubLU {
+                    ling_form: String::from("."),
+                    tags: vec![String::from("sent")],
+                    ..Default::default()
+                };
+                vec![
+                    StreamUnit::LexicalUnit(analyses),
+                    StreamUnit::lexical_unit(vec![period]),
+                ]
+            }
+            _ => vec![unit],
+        });
+        assert_eq!(
+            Stream::from(split).to_stream_string(),
+            "^word<n>$^.<sent>$ ^b$"
+        );
+    }
+
+    #[test]
+    fn parse_joined_lu_with_empty_sub_units() {
+        let sub_lu = |ling_form: &str| SubLU {
+            ling_form: String::from(ling_form),
+            ..Default::default()
+        };
+        let cases = vec![
+            ("^a+$", vec![sub_lu("a"), sub_lu("")]),
+            ("^+b$", vec![sub_lu(""), sub_lu("b")]),
+            ("^a++b$", vec![sub_lu("a"), sub_lu(""), sub_lu("b")]),
+        ];
+        for (raw, expected) in cases {
+            let (i, su) = parse_stream_unit(raw).unwrap();
+            assert_eq!(i.len(), 0);
+            assert_eq!(su, StreamUnit::JoinedLexicalUnit(vec![expected]), "{}", raw);
+            assert_eq!(Stream::from(vec![su]).to_stream_string(), raw);
+        }
+    }
+
+    #[test]
+    fn coverage_of_large_thai_data() {
+        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+        let (_, stream) = parse_stream(&raw).unwrap();
+        let known: HashSet<String> = vec![String::from("like"), String::from("dog")]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            annotate_coverage(&stream, &known),
+            vec![false, true, false, true, false]
+        );
+        assert_eq!(coverage_ratio(&stream, &known), 0.4);
+        let (_, stream) = parse_stream("^dogs/dog<n><pl>$ ^cats/cat<n><pl>$").unwrap();
+        assert_eq!(annotate_coverage(&stream, &known), vec![true, false]);
+    }
+
+    #[test]
+    fn nested_chunk_depth() {
+        let (_, inner) = parse_stream("^a$ SN<SN>{^i<prn>$}").unwrap();
+        assert_eq!(inner[0].depth(), 0);
+        assert_eq!(inner[2].depth(), 1);
+        let outer = StreamUnit::Chunk(
+            SubLU {
+                ling_form: String::from("S"),
+                ..Default::default()
+            },
+            inner,
+        );
+        assert_eq!(outer.depth(), 2);
+        assert_eq!(
+            max_chunk_depth(&[StreamUnit::Space(String::from(" ")), outer]),
+            2
+        );
+        assert_eq!(max_chunk_depth(&[]), 0);
+    }
+
+    #[test]
+    fn reject_tags_outside_tagset() {
+        let options = ParserOptions {
+            tag_validator: Some(Box::new(|tag| tag == "n" || tag == "vblex")),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("^cat<n>$ ^run<vblex><j/>$", &options).map(|stream| stream.units().len()),
+            Ok(3)
+        );
+        assert_eq!(
+            parse("^cat<n>$ ^run<xyz>$", &options),
+            Err(ParseError::UnknownTag {
+                tag: String::from("xyz"),
+                offset: 13
+            })
+        );
+    }
+
+    #[test]
+    fn biltrans_unit_into_lemma_only() {
+        let (_, stream) = parse_stream("^the/el<det>$ ^x<n>$ SN<SN>{^cat/gato<n>$}").unwrap();
+        assert_eq!(
+            Stream::from(into_lemma_only(stream)).to_stream_string(),
+            "^el<det>$ ^x<n>$ SN<SN>{^gato<n>$}"
+        );
+        let (_, ambiguous) = parse_stream_unit("^a<n>/a<adj>$").unwrap();
+        assert_eq!(ambiguous.clone().into_lemma_only(), ambiguous);
+        let options = ParserOptions {
+            compound_surface: true,
+            ..Default::default()
+        };
+        let stream = parse("^ab+c/abc<n>$", &options).unwrap();
+        let surface = match &stream.units()[0] {
+            StreamUnit::LexicalUnit(lu) => lu.surface().unwrap().clone(),
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        };
+        assert_eq!(surface.compound_parts(), vec!["ab", "c"]);
+        let joined = StreamUnit::JoinedLexicalUnit(vec![vec![surface.clone()]]);
+        assert_eq!(surface.into_lemma_only().compound_parts(), vec!["abc"]);
+        let joined = joined.into_lemma_only();
+        assert_eq!(
+            joined.first_analysis().unwrap().compound_parts(),
+            vec!["abc"]
+        );
+
+        let options = ParserOptions {
+            keep_surface: true,
+            ..Default::default()
+        };
+        let stream = parse("^dogs/dog<n><pl>$", &options).unwrap();
+        let lemma_only = stream.units()[0].clone().into_lemma_only();
+        assert_eq!(lemma_only.surface(), None);
+        assert_eq!(lemma_only.to_string(), "^dog<n><pl>$");
+    }
+
+    #[test]
+    fn last_analysis_and_reverse_iteration() {
+        let (_, su) = parse_stream_unit("^a/b/c$").unwrap();
+        assert_eq!(
+            su.last_analysis().map(|sub_lu| sub_lu.ling_form.as_str()),
+            Some("c")
+        );
+        assert_eq!(
+            su.analyses_rev()
+                .map(|sub_lu| sub_lu.ling_form.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["c", "b", "a"]
+        );
+        let (_, joined) = parse_stream_unit("^a<n>/b<n>+d<v>$").unwrap();
+        assert_eq!(
+            joined
+                .last_analysis()
+                .map(|sub_lu| sub_lu.ling_form.as_str()),
+            Some("d")
+        );
+        assert_eq!(StreamUnit::Space(String::from(" ")).last_analysis(), None);
+    }
+
+    #[test]
+    fn parse_tag_with_space() {
+        assert_eq!(parse_tag("<proper noun>"), Ok(("", "proper noun")));
+        let raw = "^York<proper noun><sg>$";
+        let (i, su) = parse_stream_unit(raw).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            su,
+            StreamUnit::lexical_unit(vec![SubLU {
+                ling_form: String::from("York"),
+                tags: vec![String::from("proper noun"), String::from("sg")],
+                ..Default::default()
+            }])
+        );
+        assert_eq!(Stream::from(vec![su]).to_stream_string(), raw);
+    }
+
+    #[test]
+    fn single_sub_lu_of_unambiguous_unit() {
+        let (_, ambiguous) = parse_stream_unit("^a<n>/b<n>$").unwrap();
+        assert_eq!(ambiguous.as_single_sub_lu(), None);
+        let (_, ambiguous) = parse_stream_unit("^surf/lem<n>/lem<v>$").unwrap();
+        assert_eq!(ambiguous.as_single_sub_lu(), None);
+        let (_, analysed) = parse_stream_unit("^surf/lem<n>$").unwrap();
+        assert_eq!(
+            analysed.as_single_sub_lu(),
+            Some(&make_sub_lu("", String::from("lem"), vec!["n"]))
+        );
+        let (_, single) = parse_stream_unit("^a$").unwrap();
+        assert_eq!(
+            single.as_single_sub_lu(),
+            Some(&SubLU {
+                ling_form: String::from("a"),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_chunk_with_custom_delimiters() {
+        let options = ParserOptions {
+            chunk_open: '«',
+            chunk_close: '»',
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("N1<SN>«^a\\«b{c\\}$ ^d$»", &options),
+            Ok(Stream::from(vec![StreamUnit::Chunk(
+                SubLU {
+                    ling_form: String::from("N1"),
+                    tags: vec![String::from("SN")],
+                    ..Default::default()
+                },
+                vec![
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("a«b{c}"),
+                        ..Default::default()
+                    }]),
+                    StreamUnit::Space(String::from(" ")),
+                    StreamUnit::lexical_unit(vec![SubLU {
+                        ling_form: String::from("d"),
+                        ..Default::default()
+                    }]),
+                ],
+            )]))
+        );
+    }
+
+    #[test]
+    fn serialize_chunk_with_custom_delimiters() {
+        let options = ParserOptions {
+            chunk_open: '«',
+            chunk_close: '»',
+            ..Default::default()
+        };
+        let stream = parse("N1<SN>«^a\\«b{c\\}$ ^d$»", &options).unwrap();
+        let text = serialize_stream_with_options(stream.units(), &options);
+        assert_eq!(text, "N1<SN>«^a\\«b\\{c\\}$ ^d$»");
+        assert_eq!(parse(&text, &options), Ok(stream));
+    }
+
+    #[test]
+    fn zip_two_joined_units() {
+        let (_, a) = parse_stream_unit("^a<n>+b<v>/c<n>+d<v>$").unwrap();
+        let (_, b) = parse_stream_unit("^x<n>+y<v>/z<n>+w<v>$").unwrap();
+        let lemmas = |group: &Vec<SubLU>| {
+            group
+                .iter()
+                .map(|sub_lu| sub_lu.ling_form.clone())
+                .collect::<Vec<String>>()
+                .join("+")
+        };
+        let zipped: Vec<(String, String)> = a
+            .zip_join_groups(&b)
+            .unwrap()
+            .into_iter()
+            .map(|(x, y)| (lemmas(x), lemmas(y)))
+            .collect();
+        assert_eq!(
+            zipped,
+            vec![
+                (String::from("a+b"), String::from("x+y")),
+                (String::from("c+d"), String::from("z+w")),
+            ]
+        );
+        let (_, shorter) = parse_stream_unit("^x<n>+y<v>$").unwrap();
+        assert_eq!(a.zip_join_groups(&shorter), None);
+        let (_, plain) = parse_stream_unit("^x$").unwrap();
+        assert_eq!(a.zip_join_groups(&plain), None);
+    }
+
+    #[test]
+    fn unterminated_format_at_end() {
+        assert_eq!(
+            parse("^a$ [abc", &ParserOptions::default()),
+            Err(ParseError::UnterminatedFormat { offset: 4 })
+        );
+        assert_eq!(
+            parse_stream_partial("^a$ [abc"),
+            Err(nom::Err::Incomplete(nom::Needed::Unknown))
+        );
+        assert_eq!(
+            parse_stream_partial("^a$ ^b\\$"),
+            Err(nom::Err::Incomplete(nom::Needed::Unknown))
+        );
+        assert_eq!(parse_stream_partial("[abc]").map(|(i, _)| i), Ok(""));
+    }
+
+    #[test]
+    fn canonical_escaping_on_serialization() {
+        let (_, over_escaped) = parse_stream("^New\\ York\\/NY<np>$").unwrap();
+        let (_, minimal) = parse_stream("^New York\\/NY<np>$").unwrap();
+        assert_eq!(
+            Stream::from(over_escaped).to_stream_string(),
+            "^New York\\/NY<np>$"
+        );
+        assert_eq!(
+            Stream::from(minimal).to_stream_string(),
+            "^New York\\/NY<np>$"
+        );
+    }
+
+    #[test]
+    fn weights_on_joined_sub_units() {
+        let options = ParserOptions {
+            weights: true,
+            ..Default::default()
+        };
+        let raw = "^a<n>:0.3+b<n>:0.7$";
+        let stream = parse(raw, &options).unwrap();
+        match &stream.units()[0] {
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                assert_eq!(analyses[0][0].weight(), Some(0.3));
+                assert_eq!(analyses[0][1].weight(), Some(0.7));
+            }
+            unit => panic!("expected a joined unit, got {:?}", unit),
+        }
+        assert!((stream.units()[0].total_weight().unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(stream.to_stream_string(), raw);
+        assert_eq!(stream.serialized_byte_len(), raw.len());
+        let stream = parse(
+            "^surf/lem<n>:0.5/lem<v>:0.2$ ^a+b/c<n>:0.3+d<v>:0.7$",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(stream.units()[0].total_weight(), Some(0.5));
+        assert!((stream.units()[2].total_weight().unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn colon_is_literal_without_weights() {
+        let stream = parse("^a:0.3<n>$", &ParserOptions::default()).unwrap();
+        assert_eq!(
+            stream.units()[0]
+                .first_analysis()
+                .map(|sub_lu| sub_lu.ling_form.as_str()),
+            Some("a:0.3")
+        );
+        assert_eq!(stream.units()[0].total_weight(), None);
+    }
+
+    #[test]
+    fn explode_ambiguous_unit() {
+        let (_, su) = parse_stream_unit("^a<n>/b<n>/c$").unwrap();
+        assert_eq!(
+            Stream::from(su.explode_analyses()).to_stream_string(),
+            "^a<n>$^b<n>$^c$"
+        );
+        let (_, su) = parse_stream_unit("^surf/lem<n>/lem<v>$").unwrap();
+        assert_eq!(
+            Stream::from(su.explode_analyses()).to_stream_string(),
+            "^lem<n>$^lem<v>$"
+        );
+        let options = ParserOptions {
+            keep_surface: true,
+            ..Default::default()
+        };
+        let stream = parse("[[t:b:1]]^dogs/dog<n><pl>/dog<vblex><pres>$", &options).unwrap();
+        let exploded = stream.units()[0].clone().explode_analyses();
+        assert_eq!(exploded.len(), 2);
+        for unit in &exploded {
+            match unit {
+                StreamUnit::LexicalUnit(lu) => {
+                    assert_eq!(lu.wordbound(), Some("t:b:1"));
+                    assert_eq!(lu.kept_surface(), Some("dogs"));
+                    assert_eq!(lu.analyses().len(), 1);
+                }
+                unit => panic!("expected a lexical unit, got {:?}", unit),
+            }
+        }
+        assert_eq!(
+            Stream::from(exploded).to_stream_string(),
+            "[[t:b:1]]^dogs/dog<n><pl>$[[t:b:1]]^dogs/dog<vblex><pres>$"
+        );
+        let space = StreamUnit::Space(String::from(" "));
+        assert_eq!(space.clone().explode_analyses(), vec![space]);
+    }
+
+    #[test]
+    fn progress_callback_fires_periodically() {
+        let raw = "^ab$ ".repeat(10);
+        let mut reports = vec![];
+        let stream = parse_stream_with_progress(&raw, 10, |offset| reports.push(offset)).unwrap();
+        assert_eq!(stream.len(), 20);
+        assert_eq!(reports, vec![10, 20, 30, 40, 50]);
+        assert_eq!(
+            parse_stream_with_progress("^a$ ^b", 10, |_| {}),
+            Err(ParseError::Syntax { offset: 4 })
+        );
+    }
+
+    #[test]
+    fn lemma_head_and_invariable() {
+        let (_, with_invariable) = parse_sub_lu("ring#ing<vblex>").unwrap();
+        assert_eq!(with_invariable.lemma_head(), "ring");
+        assert_eq!(with_invariable.invariable(), Some("ing"));
+        let (_, without_invariable) = parse_sub_lu("ring<vblex>").unwrap();
+        assert_eq!(without_invariable.lemma_head(), "ring");
+        assert_eq!(without_invariable.invariable(), None);
+    }
+
+    #[test]
+    fn debug_parse_reports_remaining_input() {
+        assert_eq!(parse_stream_debug("^a$").map(|stream| stream.len()), Ok(1));
+        let (stream, remaining) = parse_stream_debug("^a$ ^b<n$ ^c$").unwrap_err();
+        assert_eq!(Stream::from(stream).to_stream_string(), "^a$ ");
+        assert_eq!(remaining, "^b<n$ ^c$");
+    }
+
+    #[test]
+    fn plain_text_to_stream() {
+        let unknown = |word: &str| {
+            StreamUnit::lexical_unit(vec![SubLU {
+                ling_form: String::from(word),
+                flags: vec![Flag::Unanalyzed],
+                ..Default::default()
+            }])
+        };
+        assert_eq!(
+            text_to_stream("i like dogs"),
+            vec![
+                unknown("i"),
+                StreamUnit::Space(String::from(" ")),
+                unknown("like"),
+                StreamUnit::Space(String::from(" ")),
+                unknown("dogs"),
+            ]
+        );
+        assert_eq!(
+            Stream::from(text_to_stream(" a/b \n")).to_stream_string(),
+            " ^*a\\/b$ \n"
+        );
+    }
+
+    #[test]
+    fn ling_form_escaping_check() {
+        let (_, cat) = parse_sub_lu("cat<n>").unwrap();
+        assert!(!cat.ling_form_needs_escaping());
+        let (_, caret) = parse_sub_lu("a\\^b<n>").unwrap();
+        assert!(caret.ling_form_needs_escaping());
+    }
+
+    #[test]
+    fn sentence_boundaries() {
+        let input = "^a<n>$^./.<sent>$ ^b<n>$ ^EOS$ ^c$ ^eos/EOS<sym>$ ^<S>$ ^d$";
+        let (_, stream) = parse_stream(input).unwrap();
+        assert!(stream[1].is_sentence_boundary());
+        assert!(!stream[0].is_sentence_boundary());
+        assert!(!stream[5].is_sentence_boundary());
+        let sentences = split_sentences(stream.clone(), &ParserOptions::default());
+        assert_eq!(sentences.len(), 2);
+
+        let options = ParserOptions {
+            sentence_sentinel: Some(String::from("EOS")),
+            ..Default::default()
+        };
+        assert!(stream[5].is_sentence_boundary_with(&options));
+        assert!(!stream[7].is_sentence_boundary_with(&options));
+        assert!(stream[9].is_sentence_boundary_with(&options));
+        assert!(!stream[11].is_sentence_boundary_with(&options));
+        let sentences: Vec<String> = split_sentences(stream.clone(), &options)
+            .into_iter()
+            .map(|sentence| Stream::from(sentence).to_stream_string())
+            .collect();
+        assert_eq!(
+            sentences,
+            vec![
+                "^a<n>$^./.<sent>$",
+                " ^b<n>$ ^EOS$",
+                " ^c$ ^eos/EOS<sym>$",
+                " ^<S>$ ^d$"
+            ]
+        );
+
+        let options = ParserOptions {
+            sentence_sentinel: Some(String::new()),
+            ..Default::default()
+        };
+        assert!(!stream[11].is_sentence_boundary_with(&options));
+        assert_eq!(split_sentences(stream, &options).len(), 2);
+    }
+
+    #[test]
+    fn collapse_chunks_to_heads() {
+        let raw = slurp::read_all_to_string("test_data/chunks.apertium_stream").unwrap();
+        let (_, stream) = parse_stream(&raw).unwrap();
+        let flat = chunks_to_heads(stream);
+        assert_eq!(max_chunk_depth(&flat), 0);
+        assert_eq!(
+            Stream::from(flat).to_stream_string(),
+            "^SN<SN><sg>$ ^SV<SV>$\n"
+        );
+    }
+
+    #[test]
+    fn parse_blank_line_separated_documents() {
+        let documents = parse_documents("^a<n>$ ^b<v>$\n\n\n^c$>\n\n^d<n>$\n");
+        assert_eq!(documents.len(), 3);
+        assert_eq!(documents[0].as_ref().unwrap().len(), 3);
+        assert_eq!(documents[1], Err(ParseError::Syntax { offset: 3 }));
+        assert_eq!(
+            documents[2],
+            Ok(vec![
+                StreamUnit::lexical_unit(vec![make_sub_lu("", String::from("d"), vec!["n"])]),
+                StreamUnit::Space(String::from("\n")),
+            ])
+        );
+    }
+
+    #[test]
+    fn tag_bitset_positions() {
+        let schema = TagSchema::new(vec!["n", "vblex", "sg", "pl"]);
+        let bitsets = |input| match parse_stream_unit(input).unwrap().1 {
+            StreamUnit::LexicalUnit(lu) => lu
+                .analyses()
+                .iter()
+                .map(|analysis| analysis.tag_bitset(&schema))
+                .collect::<Vec<_>>(),
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        };
+        assert_eq!(
+            bitsets("^dogs/dog<n><pl>/dog<vblex><pres>$"),
+            vec![0b1001, 0b0010]
+        );
+        assert_eq!(bitsets("^dog<n><pl>$"), vec![0b1001]);
+    }
+
+    #[test]
+    fn anonymize_keeps_structure() {
+        let (_, stream) =
+            parse_stream("^dogs/dog<n><pl>$ ^take/take# out<vblex>+it<prn>$ SN<SN>{^I<prn>$}")
+                .unwrap();
+        assert_eq!(
+            Stream::from(anonymize_lemmas(stream, "X")).to_stream_string(),
+            "^X/X<n><pl>$ ^X/X#X<vblex>+X<prn>$ X<SN>{^X<prn>$}"
+        );
+        let options = ParserOptions {
+            keep_surface: true,
+            ..Default::default()
+        };
+        let stream = parse("^dogs/dog<n><pl>$", &options).unwrap();
+        let anonymized = anonymize_lemmas(stream.into_units(), "X");
+        assert_eq!(anonymized[0].surface(), Some("X"));
+    }
+
+    #[test]
+    fn count_tags() {
+        let (_, stream) = parse_stream("^ab/xy<n>$ ^cd$").unwrap();
+        assert_eq!(tag_count(&stream), 1);
+        assert_eq!(stream[1].tag_count(), 0);
+        let (_, stream) = parse_stream("^a<n><pl>+b<v>$ SN<SN>{^c<det>/d<prn>$}").unwrap();
+        assert_eq!(tag_count(&stream), 6);
+    }
+
+    #[test]
+    fn parse_quoted_lemma() {
+        let options = ParserOptions {
+            quoted_lemmas: true,
+            ..Default::default()
+        };
+        let stream = parse(r#"^"a/b"<n>$ ^"^$"$"#, &options).unwrap();
+        assert_eq!(
+            stream.units()[0],
+            StreamUnit::lexical_unit(vec![make_sub_lu("", String::from("a/b"), vec!["n"])])
+        );
+        assert_eq!(stream.to_stream_string(), r"^a\/b<n>$ ^\^\$$");
+        let stream = parse(r#"^"go#away"<vblex>$ ^go#away<vblex>$"#, &options).unwrap();
+        let quoted = stream.units()[0].first_analysis().unwrap();
+        assert_eq!((quoted.ling_form(), quoted.invariable()), ("go#away", None));
+        let unquoted = stream.units()[2].first_analysis().unwrap();
+        assert_eq!(
+            (unquoted.ling_form(), unquoted.invariable()),
+            ("go", Some("away"))
+        );
+        let raw = r#"^"go#away"<vblex>$ ^go#away<vblex>$ ^\"x<n>$ ^"a/b"<n>$"#;
+        let stream = parse(raw, &options).unwrap();
+        assert_eq!(
+            stream.units()[4].first_analysis().unwrap().ling_form(),
+            "\"x"
+        );
+        let serialized = serialize_stream_with_options(stream.units(), &options);
+        assert_eq!(
+            serialized,
+            r#"^"go#away"<vblex>$ ^go#away<vblex>$ ^\"x<n>$ ^a\/b<n>$"#
+        );
+        assert_eq!(parse(&serialized, &options), Ok(stream));
+        let (_, unquoted) = parse_stream_unit(r#"^"a/b"<n>$"#).unwrap();
+        assert_eq!(unquoted.ling_forms(), vec!["\"a", "b\""]);
+    }
+
+    #[test]
+    fn reduce_to_single_spaces() {
+        let (_, stream) = parse_stream(" \t^a$   ^b$\t[<br>]\n\n^c$ \n").unwrap();
+        assert_eq!(
+            Stream::from(single_space(stream)).to_stream_string(),
+            "^a$ ^b$ [<br>] ^c$"
+        );
+    }
+
+    #[test]
+    fn escaped_tags_round_trip_exactly() {
+        let raw = r"^x<a\>b><n>$ ^y<\c>$";
+        let (_, stream) = parse_stream(raw).unwrap();
+        let sub_lu = stream[0].first_analysis().unwrap();
+        assert_eq!(sub_lu.tags, vec!["a>b", "n"]);
+        assert_eq!(
+            sub_lu.raw_tag_spans(),
+            Some(&[String::from(r"a\>b"), String::from("n")][..])
+        );
+        let stream = Stream::from(stream);
+        assert_eq!(stream.to_stream_string(), raw);
+        assert_eq!(stream.serialized_byte_len(), raw.len());
+        assert_eq!(stream.to_canonical_stream_string(), r"^x<a\>b><n>$ ^y<c>$");
+    }
+
+    #[test]
+    fn ling_forms_of_unit() {
+        let (_, unit) = parse_stream_unit("^a/b$").unwrap();
+        assert_eq!(unit.ling_forms(), vec!["a", "b"]);
+        let (_, stream) = parse_stream("^c<n>+d<v>/e<n>$ SN<SN>{^f$} ").unwrap();
+        assert_eq!(stream[0].ling_forms(), vec!["c", "d", "e"]);
+        assert_eq!(stream[2].ling_forms(), vec!["SN"]);
+        assert!(stream[3].ling_forms().is_empty());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for raw in [
+            "^ab/xy<n>$ ^cd$",
+            "[<j>]^ab/xy<n>$[</j>]^cd$",
+            "^*dogz/@dogz<n>$\t^#go<vblex>$",
+            "^a<n><sg>+b<v><pres>$ N1<SN><a>{^c<n><pl>$ [<o>]^d$}",
+            "^\\^กา\\$<n>$ ^c\\/d/e<n>+f<v>$",
+        ] {
+            let (_, stream) = parse_stream(raw).unwrap();
+            let text: String = stream.iter().map(|unit| unit.to_string()).collect();
+            assert_eq!(text, raw);
+            assert_eq!(serialize_stream(&stream), raw);
+            assert_eq!(parse_stream(&text), Ok(("", stream)));
+        }
+    }
+
+    #[test]
+    fn filter_strips_untranslated_flags() {
+        let input = "^@a<n>$ SN<SN>{^@b$ ^c/@c$}\0^@d$\0";
+        let mut output = vec![];
+        run_filter(input.as_bytes(), &mut output, |unit| {
+            if let StreamUnit::LexicalUnit(analyses) = unit {
+                for sub_lu in analyses.iter_mut() {
+                    if *sub_lu.flag() == Flag::Untranslated {
+                        sub_lu.set_flag(Flag::Nothing);
+                    }
+                }
+            }
+        })
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "^a<n>$ SN<SN>{^b$ ^c/c$}\0^d$\0"
+        );
+    }
+
+    #[test]
+    fn plus_as_surface_compound_boundary() {
+        let (_, joined) = parse_stream_unit("^auto+mobil<n>$").unwrap();
+        assert_eq!(
+            joined,
+            StreamUnit::JoinedLexicalUnit(vec![vec![
+                make_sub_lu("", String::from("auto"), vec![]),
+                make_sub_lu("", String::from("mobil"), vec!["n"]),
+            ]])
+        );
+
+        let options = ParserOptions {
+            compound_surface: true,
+            ..Default::default()
+        };
+        let stream = parse("^auto+mobil<n>$ ^a+b/a<n>+b<v>$ ^c<n>+d<v>$", &options).unwrap();
+        let compound = stream.units()[0].as_single_sub_lu().unwrap();
+        assert_eq!(compound.lemma_head(), "automobil");
+        assert_eq!(compound.compound_parts(), vec!["auto", "mobil"]);
+        assert_eq!(compound.tags, vec!["n"]);
+        match &stream.units()[2] {
+            StreamUnit::JoinedLexicalUnit(analyses) => {
+                assert_eq!(analyses[0][0].compound_parts(), vec!["a", "b"]);
+                assert_eq!(analyses[1].len(), 2);
+            }
+            unit => panic!("expected a joined unit, got {:?}", unit),
+        }
+        assert!(matches!(&stream.units()[4], StreamUnit::JoinedLexicalUnit(a) if a[0].len() == 2));
+        assert_eq!(
+            stream.to_stream_string(),
+            "^auto+mobil<n>$ ^a+b/a<n>+b<v>$ ^c<n>+d<v>$"
+        );
+        assert_eq!(
+            stream.serialized_byte_len(),
+            stream.to_stream_string().len()
+        );
+    }
+
+    #[test]
+    fn display_sub_lu_round_trip() {
+        let (_, unit) = parse_stream_unit("^\\^ab\\$$").unwrap();
+        let sub_lu = unit.as_single_sub_lu().unwrap();
+        assert_eq!(sub_lu.to_string(), "\\^ab\\$");
+        let (_, reparsed) = parse_stream_unit(&format!("^{}$", sub_lu)).unwrap();
+        assert_eq!(reparsed, unit);
+        let (_, unit) = parse_stream_unit("^@a\\{b\\}\\[c\\]\\\\<n>$").unwrap();
+        assert_eq!(
+            unit.first_analysis().unwrap().to_string(),
+            "@a\\{b\\}\\[c\\]\\\\<n>"
+        );
+    }
+
+    #[test]
+    fn flag_symbols_invert_make_flag() {
+        for symbol in ["*", "@", "#", ""] {
+            assert_eq!(flag_symbol(&make_flag(symbol)), symbol);
+        }
+        assert_eq!(
+            Flag::UnableToGenerateOrStartOfInvariablePart.to_string(),
+            "#"
+        );
+        assert_eq!(Flag::Nothing.to_string(), "");
+    }
+
+    #[test]
+    fn count_tag_cooccurrence() {
+        let (_, stream) =
+            parse_stream("^dogs/dog<n><pl>$ ^a<pl><n>+b<v>$ SN<SN>{^c<n><sg><def>$}").unwrap();
+        let counts = tag_cooccurrence(&stream);
+        let count = |a: &str, b: &str| counts.get(&(String::from(a), String::from(b))).copied();
+        assert_eq!(count("n", "pl"), Some(2));
+        assert_eq!(count("n", "sg"), Some(1));
+        assert_eq!(count("def", "sg"), Some(1));
+        assert_eq!(count("pl", "n"), None);
+        assert_eq!(counts.len(), 4);
+    }
+
+    #[test]
+    fn lenient_missing_analysis_separator() {
+        let input = "^x$ ^ab<n> cd<n>$";
+        assert_eq!(
+            parse(input, &ParserOptions::default()),
+            Err(ParseError::Syntax { offset: 4 })
+        );
+        let options = ParserOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        let (stream, warnings) = parse_with_warnings(input, &options).unwrap();
+        assert_eq!(
+            stream.units()[2],
+            StreamUnit::lexical_unit(vec![
+                make_sub_lu("", String::from("ab"), vec!["n"]),
+                make_sub_lu("", String::from("cd"), vec!["n"]),
+            ])
+        );
+        assert_eq!(
+            warnings,
+            vec![ParseWarning::MissingAnalysisSeparator { offset: 10 }]
+        );
+        let (_, warnings) = parse_with_warnings("^New York<np>/x<n>$", &options).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn lenient_warnings_from_backtracked_branches_are_dropped() {
+        let options = ParserOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        // The basic unit fails at `+` after recovering the separator, and
+        // the joined unit that is taken instead recovers it again.
+        let input = "^ab<n> cd<n>+e<n>$ ^x<SN>{^ab<n> cd<n>+e<n>$}$";
+        let (stream, warnings) = parse_with_warnings(input, &options).unwrap();
+        assert_eq!(stream.units().len(), 3);
+        assert_eq!(
+            warnings,
+            vec![
+                ParseWarning::MissingAnalysisSeparator { offset: 6 },
+                ParseWarning::MissingAnalysisSeparator { offset: 32 },
+            ]
+        );
+        let options = ParserOptions {
+            keep_surface: true,
+            ..options
+        };
+        let (_, warnings) = parse_with_warnings("^ab/cd<n> ef<n>+g<n>$", &options).unwrap();
+        assert_eq!(
+            warnings,
+            vec![ParseWarning::MissingAnalysisSeparator { offset: 9 }]
+        );
+    }
+
+    #[test]
+    fn count_join_groups() {
+        let (_, unit) = parse_stream_unit("^a+b+c$").unwrap();
+        assert_eq!(unit.join_group_count(), Some(3));
+        let (_, unit) = parse_stream_unit("^ab/a<n>+b<v>$").unwrap();
+        assert_eq!(unit.join_group_count(), Some(2));
+        let (_, unit) = parse_stream_unit("^a/b$").unwrap();
+        assert_eq!(unit.join_group_count(), None);
+    }
+
+    #[test]
+    fn flag_conversions() {
+        assert_eq!(Flag::try_from('@'), Ok(Flag::Untranslated));
+        assert_eq!(Flag::try_from('*'), Ok(Flag::Unanalyzed));
+        assert_eq!(Flag::try_from('x'), Err(InvalidFlag(String::from("x"))));
+        assert_eq!(
+            "#".parse::<Flag>(),
+            Ok(Flag::UnableToGenerateOrStartOfInvariablePart)
+        );
+        assert_eq!("".parse::<Flag>(), Ok(Flag::Nothing));
+        assert!("**".parse::<Flag>().is_err());
+    }
+
+    #[test]
+    fn length_prefixed_round_trip() {
+        let (_, first) = parse_stream("^I/prpers<prn>$ SN<SN>{^a<det>$}").unwrap();
+        let (_, second) = parse_stream("^เป็น$\n").unwrap();
+        let mut frames = vec![];
+        write_length_prefixed(&mut frames, &first).unwrap();
+        write_length_prefixed(&mut frames, &second).unwrap();
+        assert_eq!(&frames[..4], &[0, 0, 0, 32]);
+        let mut reader = &frames[..];
+        assert_eq!(read_length_prefixed(&mut reader).unwrap(), first);
+        assert_eq!(read_length_prefixed(&mut reader).unwrap(), second);
+        let error = read_length_prefixed(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let mut invalid: &[u8] = &[0, 0, 0, 2, 0xc3, 0x28];
+        let error = read_length_prefixed(&mut invalid).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut bogus: &[u8] = &[0xff, 0xff, 0xff, 0xff, b'^', b'a', b'$'];
+        let error = read_length_prefixed(&mut bogus).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn from_str_and_parse_all() {
+        let unit: StreamUnit = "^ab<n>$".parse().unwrap();
+        assert_eq!(
+            unit,
+            StreamUnit::lexical_unit(vec![make_sub_lu("", String::from("ab"), vec!["n"])])
+        );
+        assert_eq!(
+            "^ab$ ^cd$".parse::<StreamUnit>(),
+            Err(ParseError::Syntax { offset: 4 })
+        );
+        assert_eq!(parse_all("^ab$ ^cd$").map(|stream| stream.len()), Ok(3));
+        assert_eq!(parse_all("^ab$ ^cd"), Err(ParseError::Syntax { offset: 5 }));
+    }
+
+    #[test]
+    fn strict_parse_reports_missing_dollar() {
+        let input = "^x$ ^ab";
+        let error = parse_stream_strict(input).unwrap_err();
+        assert_eq!(error, ParseError::UnterminatedLexicalUnit { offset: 4 });
+        assert_eq!(error.offset(), Some(4));
+        assert_eq!(error.reason(), "unterminated lexical unit");
+        assert_eq!(error.remaining(input), "^ab");
+        assert_eq!(
+            parse_stream_strict("^ab"),
+            Err(ParseError::UnterminatedLexicalUnit { offset: 0 })
+        );
+        assert_eq!(
+            parse_stream_strict("^x$ ^y$").map(|stream| stream.len()),
+            Ok(3)
+        );
+    }
+
+    #[test]
+    fn strip_tags_keeps_lemmas() {
+        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+        let (_, stream) = parse_stream(&raw).unwrap();
+        let lemmas: Vec<Vec<&str>> = stream.iter().map(StreamUnit::ling_forms).collect();
+        let stripped = strip_tags(stream.clone());
+        assert_eq!(tag_count(&stripped), 0);
+        assert_eq!(stripped.len(), stream.len());
+        let stripped_lemmas: Vec<Vec<&str>> = stripped.iter().map(StreamUnit::ling_forms).collect();
+        assert_eq!(stripped_lemmas, lemmas);
+
+        let raw = slurp::read_all_to_string("test_data/chunks.apertium_stream").unwrap();
+        let (_, stream) = parse_stream(&raw).unwrap();
+        assert_eq!(
+            Stream::from(strip_tags(stream)).to_stream_string(),
+            "SN{^i$} SV{^like$ ^a$}\n"
+        );
+    }
+
+    #[test]
+    fn detect_mixed_flags() {
+        let (_, stream) = parse_stream("^a/*b$ ^a/b$ ^*c/*c$ ^d+e/@f$").unwrap();
+        assert!(stream[0].has_mixed_flags());
+        assert!(!stream[2].has_mixed_flags());
+        assert!(!stream[4].has_mixed_flags());
+        assert_eq!(mixed_flag_units(&stream), vec![0, 6]);
+    }
+
+    #[test]
+    fn sub_lu_accessors() {
+        let (_, unit) = parse_stream_unit("^@dog<n><pl>$").unwrap();
+        let sub_lu = unit.first_analysis().unwrap();
+        assert_eq!(sub_lu.ling_form(), "dog");
+        assert_eq!(sub_lu.flag(), &Flag::Untranslated);
+        assert_eq!(sub_lu.tags(), &[String::from("n"), String::from("pl")]);
+    }
+
+    #[test]
+    fn build_sub_lus() {
+        let unit = StreamUnit::lexical_unit(vec![
+            SubLuBuilder::new("cats").build(),
+            SubLuBuilder::new("cat").tag("n").tag("pl").build(),
+            SubLuBuilder::new("cat")
+                .flag(Flag::Untranslated)
+                .tags(&["vblex", "pres"])
+                .build(),
+        ]);
+        assert_eq!(unit, "^cats/cat<n><pl>/@cat<vblex><pres>$".parse().unwrap());
+    }
+
+    #[test]
+    fn skip_bom_and_shebang() {
+        let input = "\u{feff}#!/usr/bin/env lt-proc\n^a<n>$ ^b$>";
+        let options = ParserOptions {
+            shebang: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse(input, &options),
+            Err(ParseError::Syntax {
+                offset: input.len() - 1
+            })
+        );
+        let stream = parse(&input[..input.len() - 5], &options).unwrap();
+        assert_eq!(stream.shebang(), Some("#!/usr/bin/env lt-proc"));
+        assert_eq!(stream.units(), vec!["^a<n>$".parse().unwrap()]);
+        assert_eq!(stream.to_stream_string(), "^a<n>$");
+        assert!(parse(input, &ParserOptions::default()).is_err());
+    }
+
+    #[test]
+    fn lexical_unit_surface_and_analyses() {
+        let lu = match parse_basic_lu("^dogs/dog<n><pl>/dog<vblex><pri><p3><sg>$") {
+            Ok(("", StreamUnit::LexicalUnit(lu))) => lu,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(lu.surface().map(SubLU::ling_form), Some("dogs"));
+        assert_eq!(lu.analyses().len(), 2);
+        assert!(lu.is_ambiguous());
+
+        let lu = LexicalUnit::from(vec![SubLuBuilder::new("dog").tag("n").build()]);
+        assert_eq!(lu.surface(), None);
+        assert_eq!(lu.analyses()[0].tags(), &[String::from("n")]);
+        assert!(!lu.is_ambiguous());
+    }
+
+    #[test]
+    fn passthrough_returns_source_verbatim() {
+        let input = "^New\\ York\\/NY<np>$ ^\\ a<x\\y>$";
+        let stream = parse(input, &ParserOptions::default())
+            .unwrap()
+            .with_source(input);
+        assert_eq!(stream.to_stream_string(), input);
+        assert_ne!(
+            Stream::from(stream.units().to_vec()).to_stream_string(),
+            input
+        );
+        assert_eq!(stream, Stream::from(stream.units().to_vec()));
+        assert_eq!(stream.serialized_byte_len(), input.len());
+        assert_eq!(stream.serialized_char_len(), input.chars().count());
+    }
+
+    #[test]
+    fn source_not_fully_parsed_is_not_kept() {
+        let input = "^a\\b$";
+        let (rest, units) = parse_stream(input).unwrap();
+        assert_eq!(rest, input);
+        let stream = Stream::from(units).with_source(input);
+        assert_eq!(stream.to_stream_string(), "");
+        assert_eq!(stream.serialized_byte_len(), 0);
+        let stream = Stream::from(parse_stream("^b$").unwrap().1).with_source("^a$");
+        assert_eq!(stream.to_stream_string(), "^b$");
+    }
+
+    #[test]
+    fn mutation_forces_reserialization() {
+        let input = "^New\\ York\\/NY<np>$";
+        let mut stream = parse(input, &ParserOptions::default())
+            .unwrap()
+            .with_source(input);
+        truncate_stream_tags(stream.units_mut(), 0);
+        assert!(stream.is_dirty());
+        assert_eq!(stream.to_stream_string(), "^New York\\/NY$");
+    }
+
+    #[test]
+    fn attach_adjacent_blanks() {
+        let (_, stream) = parse_stream("^a$ ^b$[<br>]").unwrap();
+        let units = units_with_blanks(&stream);
+        assert_eq!(units.len(), 2);
+        assert_eq!((units[0].0, units[0].2), (None, Some(" ")));
+        assert_eq!((units[1].0, units[1].2), (Some(" "), Some("<br>")));
+        assert_eq!(units[1].1, &stream[2]);
+    }
+
+    #[test]
+    fn wordbound_blank_attached_to_lu() {
+        let raw = "[[t:i:1]]^the/a<det>$ SN<SN>{[[t:b:2]]^x$}";
+        let (rest, stream) = parse_stream(raw).unwrap();
+        assert_eq!(rest, "");
+        match &stream[0] {
+            StreamUnit::LexicalUnit(lu) => {
+                assert_eq!(lu.wordbound(), Some("t:i:1"));
+                assert_eq!(lu.surface().map(SubLU::ling_form), Some("the"));
+                assert_eq!(lu.analyses()[0].tags(), &[String::from("det")]);
+            }
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        }
+        let stream = Stream::from(stream);
+        assert_eq!(stream.to_stream_string(), raw);
+        assert_eq!(stream.serialized_byte_len(), raw.len());
+    }
+
+    #[test]
+    fn stream_units_as_map_keys() {
+        let (_, stream) = parse_stream("^dog<n>$ ^dog<n>$ ^cat<n>$").unwrap();
+        let mut counts: HashMap<StreamUnit, usize> = HashMap::new();
+        for unit in [&stream[0], &stream[2]] {
+            *counts.entry(unit.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[&stream[0]], 2);
+        counts.insert(stream[4].clone(), 1);
+        assert_eq!(counts.len(), 2);
+    }
+
+    #[test]
+    fn iterate_all_sub_lus() {
+        let (_, chunk) = parse_stream_unit("N1<SN><a>{^i$ [<o>]^j$[</o>]^k$}").unwrap();
+        let (_, joined) = parse_stream_unit("^l<n>+m<v>$").unwrap();
+        let units = [chunk, joined];
+        let lemmas: Vec<&str> = sub_lus(&units).map(SubLU::ling_form).collect();
+        assert_eq!(lemmas, vec!["N1", "i", "j", "k", "l", "m"]);
+    }
+
+    #[test]
+    fn push_units_with_spaces() {
+        let mut stream = Stream::default();
+        stream.push_unit("^a$".parse().unwrap(), true);
+        stream.push_unit("^b$".parse().unwrap(), true);
+        stream.push_unit(StreamUnit::Format(String::from("<br>")), true);
+        stream.push_unit("^c$".parse().unwrap(), true);
+        assert_eq!(stream.to_stream_string(), "^a$ ^b$ [<br>]^c$");
+        let mut stream = Stream::default();
+        stream.push_unit("^a$".parse().unwrap(), false);
+        stream.push_unit("^b$".parse().unwrap(), false);
+        assert_eq!(stream.to_stream_string(), "^a$^b$");
+    }
+
+    #[test]
+    fn gen_failure_marker() {
+        let (_, literal) = parse_stream_unit("^word+?$").unwrap();
+        assert_eq!(literal.join_group_count(), Some(2));
+
+        let options = ParserOptions {
+            gen_failure_marker: true,
+            ..Default::default()
+        };
+        let stream = parse("^word+?$ ^a<n>+?/b<n>$ ^c+d$", &options).unwrap();
+        let sub_lu = stream.units()[0].as_single_sub_lu().unwrap();
+        assert!(sub_lu.gen_failed());
+        assert_eq!(sub_lu.lemma_head(), "word");
+        assert!(stream.units()[2].first_analysis().unwrap().gen_failed());
+        assert_eq!(stream.units()[4].join_group_count(), Some(2));
+        assert_eq!(stream.to_stream_string(), "^word+?$ ^a<n>+?/b<n>$ ^c+d$");
+        assert_eq!(
+            stream.serialized_byte_len(),
+            stream.to_stream_string().len()
         );
     }
 
     #[test]
-    fn lu_surface_escape() {
+    fn borrowed_sub_lus() {
+        let (rest, analyses) = parse_basic_lu_ref("^dogs/dog<n><pl>/\\^x\\/y<a\\>b>$ ^z$").unwrap();
+        assert_eq!(rest, " ^z$");
+        assert_eq!(analyses.len(), 3);
+        assert!(matches!(analyses[1].ling_form, Cow::Borrowed("dog")));
+        assert_eq!(analyses[1].tags, vec!["n", "pl"]);
+        assert!(matches!(&analyses[2].ling_form, Cow::Owned(s) if s == "^x/y"));
+        assert_eq!(analyses[2].tags, vec!["a\\>b"]);
+        let (_, sub_lu) = parse_sub_lu_ref("*<n>").unwrap();
         assert_eq!(
-            parse_stream_unit("^\\^ab\\$$"),
-            Ok((
-                "",
-                StreamUnit::LexicalUnit(vec![SubLU {
-                    ling_form: String::from("^ab$"),
-                    tags: vec![],
-                    flag: Flag::Nothing,
-                }])
-            ))
+            (sub_lu.ling_form, sub_lu.flags),
+            (Cow::Borrowed(""), vec![Flag::Unanalyzed])
+        );
+        let (_, analyses) = parse_basic_lu_ref("^*@x<n>$").unwrap();
+        assert_eq!(
+            analyses[0].flags,
+            vec![Flag::Unanalyzed, Flag::Untranslated]
         );
+        assert_eq!(analyses[0].flag(), &Flag::Unanalyzed);
+        assert_eq!(analyses[0].ling_form, "x");
     }
 
     #[test]
-    fn ambiguous_lu() {
+    fn joined_units_equal_regardless_of_order() {
+        let ab: StreamUnit = "^a<n>+b<v>$".parse().unwrap();
+        let ba: StreamUnit = "^b<v>+a<n>$".parse().unwrap();
+        assert_ne!(ab, ba);
+        assert!(ab.eq_join_unordered(&ba));
+        let aab: StreamUnit = "^a<n>+a<n>+b<v>$".parse().unwrap();
+        let abb: StreamUnit = "^a<n>+b<v>+b<v>$".parse().unwrap();
+        assert!(!aab.eq_join_unordered(&abb));
+        assert!(!ab.eq_join_unordered(&"^a<n>$".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_apertium_delimited_chunk() {
+        let raw = "^NP<SN><sg>{^the<det><def>$ ^cat<n><sg>$}$ ^VP<SV>{^sleep<vblex>$}$";
+        let (rest, stream) = parse_stream(raw).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(stream.len(), 3);
+        match parse_stream_unit(raw) {
+            Ok((_, StreamUnit::Chunk(head, children))) => {
+                assert_eq!(head.ling_form(), "NP");
+                assert_eq!(head.tags(), &[String::from("SN"), String::from("sg")]);
+                assert_eq!(children.len(), 3);
+            }
+            other => panic!("expected a chunk, got {:?}", other),
+        }
+        assert!(parse_chunk("^NP<SN>{^the<det>$}").is_err());
+        let stream = Stream::from(stream);
+        assert_eq!(stream.to_stream_string(), raw);
+        assert_eq!(stream.serialized_byte_len(), raw.len());
+        assert_eq!(max_chunk_depth(stream.units()), 1);
+        let (_, nested) = parse_stream_unit("^S<S>{^NP<SN>{^a<det>$}$}$").unwrap();
+        assert_eq!(nested.depth(), 2);
+    }
+
+    #[test]
+    fn transform_chunk_interior_only() {
+        let (_, stream) =
+            parse_stream("[<p>]^NP<SN>{[<b>]^the<det>$[</b>] ^cat<n>$}$[</p>]").unwrap();
+        let stream: Vec<StreamUnit> = stream
+            .into_iter()
+            .map(|unit| unit.with_chunk_children(strip_formats))
+            .collect();
         assert_eq!(
-            parse_stream_unit("^ab/xy$"),
-            Ok((
-                "",
-                StreamUnit::LexicalUnit(vec![
-                    SubLU {
-                        ling_form: String::from("ab"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    },
-                    SubLU {
-                        ling_form: String::from("xy"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }
-                ])
-            ))
+            serialize_stream(&stream),
+            "[<p>]^NP<SN>{^the<det>$ ^cat<n>$}$[</p>]"
+        );
+        assert_eq!(
+            serialize_stream(&strip_formats(stream)),
+            "^NP<SN>{^the<det>$ ^cat<n>$}$"
         );
     }
 
     #[test]
-    fn basic_lus() {
+    fn superblanks_are_not_formats() {
+        let raw = "[[t:s:1]] [[t:b:2]]^cat<n>$[[/]][<br>]";
+        let (rest, stream) = parse_stream(raw).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(stream[0], StreamUnit::Superblank(String::from("t:s:1")));
+        match &stream[2] {
+            StreamUnit::LexicalUnit(lu) => assert_eq!(lu.wordbound(), Some("t:b:2")),
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        }
+        assert_eq!(stream[3], StreamUnit::Superblank(String::from("/")));
+        assert_eq!(stream[4], StreamUnit::Format(String::from("<br>")));
+        let stream = Stream::from(stream);
+        assert_eq!(stream.to_stream_string(), raw);
+        assert_eq!(stream.serialized_byte_len(), raw.len());
+    }
+
+    #[test]
+    fn key_value_tags() {
+        let unit: StreamUnit = "^x<n><case:nom><f:a:b>$".parse().unwrap();
+        let pairs: Vec<(&str, Option<&str>)> = unit.first_analysis().unwrap().tag_kv().collect();
         assert_eq!(
-            parse_stream("^ab$"),
-            Ok((
-                "",
-                vec![StreamUnit::LexicalUnit(vec![SubLU {
-                    ling_form: String::from("ab"),
-                    tags: vec![],
-                    flag: Flag::Nothing,
-                }])]
-            ))
+            pairs,
+            vec![("n", None), ("case", Some("nom")), ("f", Some("a:b"))]
         );
+    }
+
+    #[test]
+    fn wordbound_blank_moves_with_its_word() {
+        assert!(parse_wordbound_lu("^cat<n>$").is_err());
+        let (_, cat) = parse_wordbound_lu("[[t:b:1]]^cat<n>$").unwrap();
+        match &cat {
+            StreamUnit::LexicalUnit(lu) => {
+                assert_eq!(lu.wordbound(), Some("t:b:1"));
+                assert_eq!(lu[0].ling_form(), "cat");
+            }
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        }
+        let (_, mut stream) = parse_stream("[[t:b:1]]^cat<n>$ ^black<adj>$").unwrap();
+        stream.swap(0, 2);
+        assert_eq!(serialize_stream(&stream), "^black<adj>$ [[t:b:1]]^cat<n>$");
+    }
+
+    #[test]
+    fn longest_unit_counts_chars() {
+        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
+        let (_, stream) = parse_stream(&raw).unwrap();
+        assert_eq!(longest_lexical_unit(&stream), Some((&stream[0], 6)));
+        let (_, stream) = parse_stream("^กากา$ ^abcde$ SN<SN>{^ภาพยนตร์$}").unwrap();
+        assert_eq!(longest_lexical_unit(&stream[..3]), Some((&stream[2], 5)));
+        let (unit, len) = longest_lexical_unit(&stream).unwrap();
         assert_eq!(
-            parse_stream("^ab$ ^cd$"),
-            Ok((
-                "",
-                vec![
-                    StreamUnit::LexicalUnit(vec![SubLU {
-                        ling_form: String::from("ab"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }]),
-                    StreamUnit::Space(String::from(" ")),
-                    StreamUnit::LexicalUnit(vec![SubLU {
-                        ling_form: String::from("cd"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }])
-                ]
-            ))
+            (unit.first_analysis().unwrap().ling_form(), len),
+            ("ภาพยนตร์", 8)
         );
+        assert!(longest_lexical_unit(&[StreamUnit::Space(String::from(" "))]).is_none());
+        let (_, stream) = parse_stream("^elephants/elephant<n><pl>$ ^surf/lem<n>/lem<v>$").unwrap();
+        assert_eq!(longest_lexical_unit(&stream), Some((&stream[0], 8)));
+        let (_, stream) = parse_stream("^surfaces/lem<n>$ ^word<n>$").unwrap();
+        assert_eq!(longest_lexical_unit(&stream), Some((&stream[2], 4)));
     }
 
     #[test]
-    fn parse_basic_tag() {
-        assert_eq!(parse_tag("<n>"), Ok(("", "n")));
+    fn parse_crlf_and_bare_cr() {
+        let (rest, stream) = parse_stream("^a$\r\n^b$\r^c$").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(stream[1], StreamUnit::Space(String::from("\r\n")));
+        assert_eq!(stream[3], StreamUnit::Space(String::from("\r")));
+        assert_eq!(serialize_stream(&stream), "^a$\r\n^b$\r^c$");
     }
 
     #[test]
-    fn parse_basic_stream_with_tags() {
+    fn parse_null_flush_units() {
+        let (rest, stream) = parse_stream("^a$\0^b$").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(stream.len(), 3);
+        assert_eq!(stream[1], StreamUnit::NullFlush);
         assert_eq!(
-            parse_stream("^ab/xy<n>$ ^cd$"),
-            Ok((
-                "",
-                vec![
-                    StreamUnit::LexicalUnit(vec![
-                        SubLU {
-                            ling_form: String::from("ab"),
-                            tags: vec![],
-                            flag: Flag::Nothing,
-                        },
-                        SubLU {
-                            ling_form: String::from("xy"),
-                            tags: vec![String::from("n")],
-                            flag: Flag::Nothing,
-                        }
-                    ]),
-                    StreamUnit::Space(String::from(" ")),
-                    StreamUnit::LexicalUnit(vec![SubLU {
-                        ling_form: String::from("cd"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }])
-                ]
-            ))
+            parse_stream_unit("\0^b$"),
+            Ok(("^b$", StreamUnit::NullFlush))
         );
+        let stream = Stream::from(stream);
+        assert_eq!(stream.to_stream_string().as_bytes(), b"^a$\0^b$");
+        assert_eq!(stream.serialized_byte_len(), 7);
     }
 
     #[test]
-    fn parse_basic_stream_with_tags_sans_space() {
+    fn parse_rejects_streams_over_limits() {
+        let options = ParserOptions {
+            max_units: Some(2),
+            ..Default::default()
+        };
+        assert!(parse("^a$ ", &options).is_ok());
         assert_eq!(
-            parse_stream("^ab/xy<n>$^cd$"),
-            Ok((
-                "",
-                vec![
-                    StreamUnit::LexicalUnit(vec![
-                        SubLU {
-                            ling_form: String::from("ab"),
-                            tags: vec![],
-                            flag: Flag::Nothing,
-                        },
-                        SubLU {
-                            ling_form: String::from("xy"),
-                            tags: vec![String::from("n")],
-                            flag: Flag::Nothing,
-                        }
-                    ]),
-                    StreamUnit::LexicalUnit(vec![SubLU {
-                        ling_form: String::from("cd"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }])
-                ]
-            ))
+            parse("^a$ ^b$", &options),
+            Err(ParseError::TooManyUnits { offset: 4 })
+        );
+        let options = ParserOptions {
+            max_chunk_depth: Some(1),
+            ..Default::default()
+        };
+        assert!(parse("A<a>{^b$}", &options).is_ok());
+        assert_eq!(
+            parse("A<a>{B<b>{^c$}}", &options),
+            Err(ParseError::ChunkTooDeep { offset: 10 })
+        );
+        let options = ParserOptions {
+            max_unit_bytes: Some(5),
+            ..Default::default()
+        };
+        assert!(parse("^abc$ ^d$", &options).is_ok());
+        assert_eq!(
+            parse("^a$ ^abcd$", &options),
+            Err(ParseError::UnitTooLarge { offset: 4 })
         );
     }
 
     #[test]
-    fn parse_basic_stream_with_tags_sans_space_with_format() {
+    fn limits_apply_inside_chunks() {
+        let options = ParserOptions {
+            max_units: Some(4),
+            ..Default::default()
+        };
+        assert!(parse("A<a>{^b$ ^c$}", &options).is_ok());
         assert_eq!(
-            parse_stream("[<j>]^ab/xy<n>$[</j>]^cd$"),
-            Ok((
-                "",
-                vec![
-                    StreamUnit::Format(String::from("<j>")),
-                    StreamUnit::LexicalUnit(vec![
-                        SubLU {
-                            ling_form: String::from("ab"),
-                            tags: vec![],
-                            flag: Flag::Nothing,
-                        },
-                        SubLU {
-                            ling_form: String::from("xy"),
-                            tags: vec![String::from("n")],
-                            flag: Flag::Nothing,
-                        }
-                    ]),
-                    StreamUnit::Format(String::from("</j>")),
-                    StreamUnit::LexicalUnit(vec![SubLU {
-                        ling_form: String::from("cd"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }])
-                ]
-            ))
+            parse("A<a>{^b$ ^c$ ^d$ ^e$}", &options),
+            Err(ParseError::TooManyUnits { offset: 13 })
+        );
+        let options = ParserOptions {
+            max_unit_bytes: Some(16),
+            ..Default::default()
+        };
+        assert!(parse("^a$ A<a>{^b$ ^cd$}", &options).is_ok());
+        let oversized = format!("^a$ ^{}$ ^b$", "x/".repeat(10_000));
+        assert_eq!(
+            parse(&oversized, &options),
+            Err(ParseError::UnitTooLarge { offset: 4 })
         );
+        assert_eq!(
+            parse("A<a>{^b$ ^cdefghijklmnopq$}", &options),
+            Err(ParseError::UnitTooLarge { offset: 0 })
+        );
+        assert_eq!(
+            parse("^a$ %", &options),
+            Err(ParseError::Syntax { offset: 4 })
+        );
+        let malformed = format!("^a<$ {}", "^b$ ".repeat(10));
+        assert_eq!(
+            parse(&malformed, &options),
+            Err(ParseError::Syntax { offset: 0 })
+        );
+        let validated = |max_unit_bytes| ParserOptions {
+            max_unit_bytes,
+            tag_validator: Some(Box::new(|tag| tag != "bad")),
+            ..Default::default()
+        };
+        for input in &[
+            "^a$ A<bad>x ^b$ ^c$",
+            "^a$ ^b<n>/c<bad> ^d$",
+            "^a$ [b ^c$ ^d$",
+        ] {
+            assert_eq!(
+                parse(input, &validated(Some(8))),
+                parse(input, &validated(None))
+            );
+        }
     }
 
     #[test]
-    fn parse_joined_lu_basic() {
+    fn write_stream_trailing_newline() {
+        for input in &["^a$ ^b$", "^a$ ^b$\n"] {
+            let (rest, stream) = parse_stream(input).unwrap();
+            assert_eq!(rest, "");
+            assert_eq!(
+                stream.last() == Some(&StreamUnit::Space(String::from("\n"))),
+                input.ends_with('\n')
+            );
+            let mut out = vec![];
+            write_stream(&mut out, &stream, false).unwrap();
+            assert_eq!(out, input.as_bytes());
+            let mut out = vec![];
+            write_stream(&mut out, &stream, true).unwrap();
+            assert_eq!(out, b"^a$ ^b$\n");
+        }
+    }
+
+    #[test]
+    fn parse_invariable_multiword_hash() {
+        let (_, flagged) = parse_sub_lu_basic("#foo$").unwrap();
         assert_eq!(
-            parse_stream_unit("^ab/xy<n>+tx<a>$"),
-            Ok((
-                "",
-                StreamUnit::JoinedLexicalUnit(vec![
-                    vec![SubLU {
-                        ling_form: String::from("ab"),
-                        tags: vec![],
-                        flag: Flag::Nothing,
-                    }],
-                    vec![
-                        SubLU {
-                            ling_form: String::from("xy"),
-                            tags: vec![String::from("n")],
-                            flag: Flag::Nothing,
-                        },
-                        SubLU {
-                            ling_form: String::from("tx"),
-                            tags: vec![String::from("a")],
-                            flag: Flag::Nothing,
-                        }
-                    ],
-                ]),
-            ))
+            flagged.flag(),
+            &Flag::UnableToGenerateOrStartOfInvariablePart
         );
+        assert_eq!(flagged.ling_form(), "foo");
+        assert_eq!(flagged.invariable(), None);
+        let (_, multiword) = parse_sub_lu_basic("take#off<vblex><pres>$").unwrap();
+        assert_eq!(multiword.flag(), &Flag::Nothing);
+        assert_eq!(multiword.ling_form(), "take");
+        assert_eq!(multiword.invariable(), Some("off"));
+        assert_eq!(multiword.tags(), &vec!["vblex", "pres"]);
+        let input = "^take#off<vblex>$";
+        let (_, unit) = parse_basic_lu(input).unwrap();
+        assert_eq!(unit.to_string(), input);
     }
 
     #[test]
-    fn parse_chunk_with_format() {
+    fn parse_escaped_plus_in_ling_form() {
+        let input = r"^a\+b<n>$";
+        let (rest, unit) = parse_stream_unit(input).unwrap();
+        assert_eq!(rest, "");
+        match &unit {
+            StreamUnit::LexicalUnit(lu) => {
+                assert_eq!(lu.len(), 1);
+                assert_eq!(lu[0].ling_form(), "a+b");
+                assert_eq!(lu[0].tags(), &vec!["n"]);
+            }
+            _ => panic!("expected a lexical unit, got {:?}", unit),
+        }
+        assert_eq!(unit.to_string(), input);
+    }
+
+    #[test]
+    fn dedup_adjacent_lus_drops_repeats() {
+        let (_, stream) = parse_stream("^a$ ^a$ ^b$").unwrap();
         assert_eq!(
-            parse_stream_unit("N1<SN><a>{^i$ [<o>]^j$[</o>]^k$}"),
-            Ok((
-                "",
-                StreamUnit::Chunk(
-                    SubLU {
-                        ling_form: String::from("N1"),
-                        tags: vec![String::from("SN"), String::from("a")],
-                        flag: Flag::Nothing,
-                    },
-                    vec![
-                        StreamUnit::LexicalUnit(vec![SubLU {
-                            ling_form: String::from("i"),
-                            tags: vec![],
-                            flag: Flag::Nothing,
-                        }]),
-                        StreamUnit::Space(String::from(" ")),
-                        StreamUnit::Format(String::from("<o>")),
-                        StreamUnit::LexicalUnit(vec![SubLU {
-                            ling_form: String::from("j"),
-                            tags: vec![],
-                            flag: Flag::Nothing,
-                        }]),
-                        StreamUnit::Format(String::from("</o>")),
-                        StreamUnit::LexicalUnit(vec![SubLU {
-                            ling_form: String::from("k"),
-                            tags: vec![],
-                            flag: Flag::Nothing,
-                        }]),
-                    ],
-                ),
-            ))
+            serialize_stream(&dedup_adjacent_lus(stream.clone(), true)),
+            "^a$ ^b$"
+        );
+        assert_eq!(dedup_adjacent_lus(stream.clone(), false), stream);
+        let (_, stream) = parse_stream("^a$^a$ ^b$^a$").unwrap();
+        assert_eq!(
+            serialize_stream(&dedup_adjacent_lus(stream, false)),
+            "^a$ ^b$^a$"
         );
+        for input in &["^a$[<b>]^a$", "^a$ [[t:b:1]] ^a$"] {
+            let (_, stream) = parse_stream(input).unwrap();
+            assert_eq!(serialize_stream(&dedup_adjacent_lus(stream, true)), *input);
+        }
     }
 
     #[test]
-    fn parse_escape_bracket() {
-        let raw = "^\\]<vblex><pres>$";
-        let (i, _) = parse_stream_unit(raw).unwrap();
-        assert_eq!(i.len(), 0);
+    fn keep_surface_separates_surface_from_analyses() {
+        let options = ParserOptions {
+            keep_surface: true,
+            ..Default::default()
+        };
+        let input = "^dogs/dog<n><pl>/dog<vblex><pres><p3><sg>$ ^a$";
+        let stream = parse(input, &options).unwrap();
+        assert_eq!(stream.units()[0].surface(), Some("dogs"));
+        match &stream.units()[0] {
+            StreamUnit::LexicalUnit(lu) => {
+                assert_eq!(lu.analyses().len(), 2);
+                assert_eq!(lu.analyses()[0].ling_form(), "dog");
+                assert_eq!(lu.analyses()[0].tags(), &vec!["n", "pl"]);
+                assert!(lu.surface().is_none());
+            }
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        }
+        assert_eq!(stream.units()[2].surface(), None);
+        assert_eq!(stream.to_stream_string(), input);
+        assert_eq!(stream.serialized_byte_len(), input.len());
+        assert_eq!(surface_text(stream.units()), "dogs a");
+        let input = "^a\\/b/a\\/b<n>$ ^the/the<det><def>$";
+        let stream = parse(input, &options).unwrap();
+        assert_eq!(stream.units()[0].surface(), Some("a/b"));
+        assert_eq!(surface_text(stream.units()), "a/b the");
+        assert_eq!(serialize_stream(stream.units()), input);
+        assert_eq!(stream.units()[0].serialized_len(), 14);
+        let (_, default) = parse_stream(input).unwrap();
+        assert_eq!(default[0].surface(), None);
     }
 
     #[test]
-    fn parse_at_lu() {
-        let raw = "^\\@<det><ind><sg>$";
-        let (i, _) = parse_stream_unit(raw).unwrap();
-        assert_eq!(i.len(), 0);
+    fn parse_stacked_flags() {
+        let input = "^*@word<n>$";
+        let (rest, unit) = parse_stream_unit(input).unwrap();
+        assert_eq!(rest, "");
+        let sub_lu = unit.first_analysis().unwrap();
+        assert_eq!(sub_lu.flags(), &[Flag::Unanalyzed, Flag::Untranslated]);
+        assert_eq!(sub_lu.flag(), &Flag::Unanalyzed);
+        assert_eq!(sub_lu.ling_form(), "word");
+        assert_eq!(unit.to_string(), input);
+        assert_eq!(unit.serialized_len(), input.len());
+        assert_eq!(make_flags("@*"), vec![Flag::Untranslated, Flag::Unanalyzed]);
+        let built = SubLuBuilder::new("word")
+            .flag(Flag::Unanalyzed)
+            .flag(Flag::Untranslated)
+            .tag("n")
+            .build();
+        assert_eq!(&built, sub_lu);
     }
 
     #[test]
-    fn parse_special_lemma() {
-        let raw = "^*t<det><ind><sg>$";
-        let (i, su) = parse_stream_unit(&raw).unwrap();
-        assert_eq!(i.len(), 0);
+    fn parse_empty_lexical_unit() {
+        let empty = StreamUnit::lexical_unit(vec![SubLU::default()]);
+        assert_eq!(parse_basic_lu("^$ ^a$"), Ok((" ^a$", empty.clone())));
+        assert_eq!(parse_stream_unit("^$"), Ok(("", empty.clone())));
+        assert_eq!(empty.to_string(), "^$");
+    }
+
+    #[test]
+    fn find_empty_units() {
+        let (_, stream) = parse_stream("^a/b<n>$ ^$ ^c+d$ A<a>{^/$} ^/e$").unwrap();
+        assert!(!all_units_analyzed(&stream));
+        assert_eq!(empty_unit_indices(&stream), vec![2, 6]);
+        let (_, stream) = parse_stream("^a$ A<a>{^b<n>$}").unwrap();
+        assert!(all_units_analyzed(&stream));
+        assert!(empty_unit_indices(&stream).is_empty());
+    }
+
+    #[test]
+    fn split_tag_alternatives() {
+        let (_, sub_lu) = parse_sub_lu("x<n><m|f>").unwrap();
+        assert_eq!(sub_lu.tag_alternatives(), vec![vec!["n"], vec!["m", "f"]]);
+        assert_eq!(sub_lu.tags(), &vec!["n", "m|f"]);
+    }
+
+    #[test]
+    fn parse_reports_empty_tag() {
+        let options = ParserOptions::default();
+        let err = parse("^b$ ^a<>$", &options).unwrap_err();
+        assert_eq!(err, ParseError::EmptyTag { offset: 6 });
+        assert_eq!(err.to_string(), "empty tag <> at byte 6");
+        assert!(matches!(parse_stream("^a<>$"), Err(nom::Err::Failure(_))));
         assert_eq!(
-            su,
-            StreamUnit::LexicalUnit(vec![SubLU {
-                ling_form: String::from("t"),
-                tags: vec![String::from("det"), String::from("ind"), String::from("sg")],
-                flag: Flag::Unanalyzed,
-            }])
-        )
+            "^b<>$".parse::<StreamUnit>(),
+            Err(ParseError::EmptyTag { offset: 2 })
+        );
+        assert_eq!(
+            parse_all("^a$ ^b<>$"),
+            Err(ParseError::EmptyTag { offset: 6 })
+        );
+        let (stream, remaining) = parse_stream_debug("^a$ ^b<>$").unwrap_err();
+        assert_eq!(Stream::from(stream).to_stream_string(), "^a$ ");
+        assert_eq!(remaining, "^b<>$");
+        let (_, errors) = parse_recovering("^a$ ^b<>$");
+        assert_eq!(errors, vec![ParseError::EmptyTag { offset: 6 }]);
     }
 
     #[test]
-    fn parse_special_lemma_only() {
-        let raw = "^*<det><ind><sg>$";
-        let (i, su) = parse_stream_unit(&raw).unwrap();
-        assert_eq!(i.len(), 0);
+    fn parse_stream_checked_reports_leftover() {
+        let input = "^a$ ^b$ %^c$ ^d$ ^e$ ^f$ ^g$";
+        let (rest, _) = parse_stream(input).unwrap();
+        assert_eq!(rest.len(), input.len() - 8);
+        let err = parse_stream_checked(input).unwrap_err();
         assert_eq!(
-            su,
-            StreamUnit::LexicalUnit(vec![SubLU {
-                ling_form: String::from(""),
-                tags: vec![String::from("det"), String::from("ind"), String::from("sg")],
-                flag: Flag::Unanalyzed,
-            }])
-        )
+            err,
+            ParseError::TrailingInput {
+                offset: 8,
+                leftover: String::from("%^c$ ^d$ ^e$ ^f$"),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "unparsed input \"%^c$ ^d$ ^e$ ^f$\" at byte 8"
+        );
+        assert_eq!(parse_stream_checked("^a$ ^b$").unwrap().len(), 3);
     }
 
     #[test]
-    fn parse_large_thai_data() {
-        let raw = slurp::read_all_to_string("test_data/i_like_a_dog_sent.apertium_stream").unwrap();
-        let (i, stream) = parse_stream(&raw).unwrap();
-        assert_eq!(i.len(), 0);
+    fn chunk_trailing_dollar_round_trips() {
+        let modern = "^NP<SN>{^the<det>$ ^NN<n>{^cat<n>$}$}$ ^a$";
+        let older = "^NP<SN>{^the<det>$ ^NN<n>{^cat<n>$}} ^a$";
+        let options = ParserOptions::default();
+        let stream = parse(modern, &options).unwrap();
         assert_eq!(
-            stream
-                .into_iter()
-                .filter_map(|su| match su {
-                    StreamUnit::Space(_) => None,
-                    StreamUnit::LexicalUnit(analyses) => Some(analyses[0].ling_form.clone()),
-                    _ => Some(String::from("_")),
-                })
-                .count(),
-            5
+            serialize_stream_with_options(stream.units(), &options),
+            modern
         );
+        assert!(parse(older, &options).is_err());
+        let options = ParserOptions {
+            chunk_trailing_dollar: false,
+            ..Default::default()
+        };
+        let stream = parse(older, &options).unwrap();
+        assert_eq!(stream.units().len(), 3);
+        assert_eq!(
+            serialize_stream_with_options(stream.units(), &options),
+            older
+        );
+        assert_eq!(serialize_stream(stream.units()), modern);
+        assert!(parse(modern, &options).is_err());
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn normalize_ling_forms_nfc() {
+        // "กุ่" with the vowel and tone marks in either order.
+        let tone_first = "^\u{e01}\u{e48}\u{e38}<n>$";
+        let vowel_first = "^\u{e01}\u{e38}\u{e48}<n>$";
+        let (_, a) = parse_stream(tone_first).unwrap();
+        let (_, b) = parse_stream(vowel_first).unwrap();
+        assert_ne!(a, b);
+        let options = ParserOptions {
+            normalize_nfc: true,
+            ..Default::default()
+        };
+        assert_eq!(parse(tone_first, &options), parse(vowel_first, &options));
+        let (mut a, mut b) = (a, b);
+        normalize_stream_nfc(&mut a);
+        normalize_stream_nfc(&mut b);
+        assert_eq!(a, b);
+        let options = ParserOptions {
+            quoted_lemmas: true,
+            ..options
+        };
+        let stream = parse("^\"\u{e01}\u{e48}\u{e38}\"<n>$", &options).unwrap();
+        assert_eq!(stream.to_stream_string(), tone_first);
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn normalize_compound_surface_nfc() {
+        let options = ParserOptions {
+            compound_surface: true,
+            ..Default::default()
+        };
+        let mut stream = parse("^e\u{301}e\u{301}+x/foo<n>$", &options)
+            .unwrap()
+            .into_units();
+        normalize_stream_nfc(&mut stream);
+        match &stream[0] {
+            StreamUnit::LexicalUnit(lu) => {
+                assert_eq!(lu[0].compound_parts(), vec!["\u{e9}\u{e9}", "x"])
+            }
+            unit => panic!("expected a lexical unit, got {:?}", unit),
+        }
+        assert_eq!(serialize_stream(&stream), "^\u{e9}\u{e9}+x/foo<n>$");
     }
 }
@@ -0,0 +1,65 @@
+#![cfg(feature = "serde")]
+
+use reinars::{export_ndjson, parse_all, StreamUnit};
+use std::fs::File;
+use std::io::BufReader;
+
+fn export(reader: impl std::io::BufRead, include_blanks: bool) -> Vec<String> {
+    let mut out = vec![];
+    export_ndjson(reader, &mut out, include_blanks).unwrap();
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+fn read_back(lines: &[String]) -> Vec<StreamUnit> {
+    lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn export_ndjson_per_lexical_unit() {
+    let file = File::open("test_data/multi_segment.apertium_stream").unwrap();
+    let lines = export(BufReader::new(file), false);
+    assert_eq!(lines.len(), 6);
+    assert_eq!(
+        lines[0],
+        "{\"type\":\"LexicalUnit\",\"value\":{\"readings\":[\
+         {\"ling_form\":\"I\",\"flags\":[],\"tags\":[],\"joins_next\":false,\
+         \"invariable\":null,\"weight\":null,\"raw_tag_spans\":null,\
+         \"compound_boundaries\":[],\"gen_failed\":false,\"delimited_chunk\":false},\
+         {\"ling_form\":\"prpers\",\"flags\":[],\"tags\":[\"prn\"],\"joins_next\":false,\
+         \"invariable\":null,\"weight\":null,\"raw_tag_spans\":null,\
+         \"compound_boundaries\":[],\"gen_failed\":false,\"delimited_chunk\":false}],\
+         \"wordbound\":null,\"surface\":null}}"
+    );
+}
+
+#[test]
+fn export_ndjson_across_small_reads() {
+    let file = File::open("test_data/multi_segment.apertium_stream").unwrap();
+    let whole = export(BufReader::new(file), true);
+    let file = File::open("test_data/multi_segment.apertium_stream").unwrap();
+    assert_eq!(export(BufReader::with_capacity(3, file), true), whole);
+}
+
+#[test]
+fn export_ndjson_reads_back_as_units() {
+    let raw = "[[t:b:1]]^*@a\"b<x\\>y>$ [x]^go# out<vblex>+it<prn>$ SN<SN>{^c<n>$}";
+    let lines = export(raw.as_bytes(), true);
+    assert_eq!(lines.len(), 6);
+    assert_eq!(read_back(&lines), parse_all(raw).unwrap());
+    let lines = export(raw.as_bytes(), false);
+    assert_eq!(lines.len(), 3);
+}
+
+#[test]
+fn export_ndjson_reports_unparsed_input() {
+    let mut out = vec![];
+    let err = export_ndjson("^a$ %".as_bytes(), &mut out, false).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}